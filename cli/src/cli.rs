@@ -1,10 +1,15 @@
 use std::borrow::Cow;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::time::SystemTime;
 
 use log::*;
 use anyhow::*;
 use clap::{Arg, ArgAction, Command};
 use arboard::Clipboard;
+use rand::Rng;
 use ureq::Agent;
+use zeroize::Zeroizing;
 use ureq::tls::{TlsConfig, RootCerts};
 
 #[cfg(target_os = "linux")]
@@ -12,7 +17,11 @@ use arboard::SetExtLinux;
 
 use passe_core::*;
 use passe_core::password::*;
-use passe_core::config::{Config, Domains};
+use passe_core::config::{Config, DomainConfig, Resolution, MIN_LENGTH, MAX_LENGTH};
+use passe_core::encryption::{SyncKey, NONCE_LENGTH, SALT_LENGTH};
+use passe_core::sync::{SyncResponse, Vault, VaultResponse};
+use passe_core::verifier::{Fingerprint, Verifier};
+use passe_core::policy::{CharClass, Policy};
 use passe_core::auth::*;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
@@ -21,10 +30,25 @@ pub fn main() -> Result<()> {
 	env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
 	let app = Command::new("passe")
 		.arg(Arg::new("edit").long("edit").action(ArgAction::SetTrue))
+		.arg(Arg::new("rotate").long("rotate").action(ArgAction::SetTrue).help("Change a domain's password by incrementing its rotation"))
 		.arg(Arg::new("sync").long("sync").action(ArgAction::SetTrue))
-		.arg(Arg::new("full").long("full").action(ArgAction::SetTrue).help("Do a full (initial) sync"))
+		.arg(Arg::new("full").long("full").action(ArgAction::SetTrue).help("Do a full (initial) sync, uploading all domains and downloading everything"))
+		.arg(Arg::new("sync-passphrase").long("sync-passphrase").action(ArgAction::SetTrue).help("Encrypt synced data with a passphrase, so that the server can't read it, then sync"))
+		.arg(Arg::new("remember-master").long("remember-master").action(ArgAction::SetTrue).help("Store a verifier for your master password, to catch typos"))
+		.arg(Arg::new("identity").long("identity").short('i').global(true).help("Master password identity, for new domains, --defaults, --remember-master and migrate-master"))
+		.arg(Arg::new("alias").long("alias").value_name("canonical").help("Make this domain an alias of another, sharing its password"))
+		.arg(Arg::new("remove-alias").long("remove-alias").action(ArgAction::SetTrue))
+		.arg(Arg::new("app").long("app").value_name("app-id").help("Use this domain for a mobile app whose package name doesn't match it"))
+		.arg(Arg::new("remove-app").long("remove-app").value_name("app-id"))
+		.arg(Arg::new("rename").long("rename").value_name("new-domain").help("Move a domain's config to a new name, keeping its password"))
+		.arg(Arg::new("defaults").long("defaults").action(ArgAction::SetTrue).help("Edit the defaults for new domains (or with --resolve, resolve a conflict in settings)"))
+		.arg(Arg::new("resolve").long("resolve").value_parser(["local", "remote"]).help("Resolve a sync conflict by keeping the local or remote change"))
+		.arg(Arg::new("keep-duplicate").long("keep-duplicate").action(ArgAction::SetTrue).help("Keep this config (exactly as listed) for a domain with duplicate configs, removing the others"))
+		.arg(Arg::new("delete").long("delete").action(ArgAction::SetTrue).help("Remove a domain's config"))
 		.arg(Arg::new("list").long("list").short('l').action(ArgAction::SetTrue))
+		.arg(Arg::new("pending").long("pending").action(ArgAction::SetTrue).help("List changes which haven't been synced"))
 		.arg(Arg::new("domain").required(false))
+		.subcommand(Command::new("migrate-master").about("Change your master password, one domain at a time"))
 	;
 
 	let opts = app.get_matches();
@@ -32,43 +56,153 @@ pub fn main() -> Result<()> {
 	
 	let mut config = Config::load_user()?;
 	let get_domain = || opts.get_one::<String>("domain").ok_or_else(|| anyhow!("Domain required"));
+	let identity = opts.get_one::<String>("identity").map(|s| s.as_str());
 	
-	if opts.get_flag("list") {
+	if opts.subcommand_matches("migrate-master").is_some() {
+		migrate_master(&mut config, identity)?;
+	} else if opts.get_flag("list") {
 		for domain in config.domain_list() {
 			println!("{}", domain)
 		}
-	} else if opts.get_flag("sync") {
-		info!("Syncing ...");
-
+		for (alias, canonical) in config.alias_list() {
+			println!("{} -> {}", alias, canonical)
+		}
+		for (app, domain) in config.app_list() {
+			println!("{} -> {} (app)", app, domain)
+		}
+		for (domain, duplicates) in config.duplicates() {
+			println!("{} has duplicate configs: {} (keep one with --keep-duplicate)", domain, duplicates.join(", "))
+		}
+	} else if opts.get_flag("pending") {
+		for change in config.pending_changes() {
+			println!("{}", change)
+		}
+	} else if opts.get_flag("sync") || opts.get_flag("sync-passphrase") {
 		let agent = Agent::config_builder()
 			.tls_config(TlsConfig::builder().root_certs(RootCerts::PlatformVerifier).build())
 			.build()
 			.new_agent();
 
-		let changes = if opts.contains_id("full") {
-			config.full_changes()
+		if opts.get_flag("sync-passphrase") {
+			set_sync_passphrase(&agent, &mut config)?;
+		}
+
+		// the response is applied to a reloaded user.json, so save any changes first
+		config.save_user()?;
+
+		info!("Syncing ...");
+		let full = opts.get_flag("full");
+		if config.uses_encrypted_sync() {
+			vault_sync(&agent, &mut config, full)?;
 		} else {
-			config.changes().to_owned()
-		};
-		let sync_result: Domains = authed_request(&agent, &mut config, "db", Some(&changes))?;
-		config.update_after_sync(sync_result);
+			plaintext_sync(&agent, &mut config, full)?;
+		}
+		for conflict in config.conflicts() {
+			print_conflict(conflict.domain, conflict.local, conflict.remote, conflict.domain);
+		}
+		if let Some(remote) = config.settings_conflict() {
+			print_conflict("Defaults", Some(&config.defaults), Some(&remote.defaults), "--defaults");
+		}
+	} else if opts.get_flag("remember-master") {
+		let password = prompt_secret("Master password: ")?;
+		let confirmation = prompt_secret("Confirm master password: ")?;
+		if password != confirmation {
+			bail!("Passwords don't match");
+		}
+		let mut salt = [0u8; 16];
+		rand::rng().fill_bytes(&mut salt);
+		config.set_verifier(identity, Verifier::new(Password(&password), salt, now()?));
+		println!("Fingerprint: {}", Fingerprint::new(Password(&password)));
+	} else if let Some(canonical) = opts.get_one::<String>("alias") {
+		let domain = get_domain().context("for --alias")?;
+		config.add_alias(domain, canonical)?;
+	} else if opts.get_flag("remove-alias") {
+		let domain = get_domain().context("for --remove-alias")?;
+		config.remove_alias(domain)?;
+	} else if let Some(app) = opts.get_one::<String>("app") {
+		let domain = get_domain().context("for --app")?;
+		config.set_app_domain(app, domain)?;
+	} else if let Some(app) = opts.get_one::<String>("remove-app") {
+		config.remove_app(app)?;
+	} else if let Some(to) = opts.get_one::<String>("rename") {
+		let domain = get_domain().context("for --rename")?;
+		config.rename(domain, to)?;
+	} else if let Some(resolution) = opts.get_one::<String>("resolve") {
+		let resolution = if resolution == "local" { Resolution::Local } else { Resolution::Remote };
+		if opts.get_flag("defaults") {
+			config.resolve_settings_conflict(resolution)?;
+		} else {
+			let domain = get_domain().context("for --resolve")?;
+			config.resolve_conflict(domain, resolution)?;
+		}
+	} else if opts.get_flag("defaults") {
+		match identity {
+			None => {
+				let mut defaults = config.defaults.clone();
+				edit_domain_config(&mut defaults)?;
+				config.set_defaults(defaults)?;
+			},
+			Some(name) => {
+				// a new identity starts from the global defaults
+				let mut defaults = config.defaults_for(Some(name)).or_else(|_| config.defaults_for(None))?;
+				edit_domain_config(&mut defaults)?;
+				config.set_identity_defaults(name, defaults, now()?)?;
+			},
+		}
+	} else if opts.get_flag("keep-duplicate") {
+		let domain = get_domain().context("for --keep-duplicate")?;
+		config.keep_duplicate(domain)?;
+	} else if opts.get_flag("delete") {
+		let domain = get_domain().context("for --delete")?;
+		let resolved = config.resolve(domain).into_owned();
+		if config.for_domain(&resolved).explicit().is_none() {
+			bail!("{} is not configured", &resolved);
+		}
+		if confirm(&format!("Delete config for {}?", &resolved))? {
+			config.remove(&resolved)?;
+		}
+	} else if opts.get_flag("rotate") {
+		let domain = get_domain().context("for --rotate")?;
+		let rotation = config.rotate(domain, now()?)?;
+		println!("Rotated {} to rotation {}", domain, rotation);
 	} else if opts.get_flag("edit") {
 		let domain = get_domain().context("for --edit")?;
-		let mut domain_config = config.for_domain(&domain).underlying().to_owned();
+		let mut domain_config = config.for_domain_as(domain, identity)?.underlying();
 		edit_setting("Note", &mut domain_config.note)?;
-		edit_setting("Suffix", &mut domain_config.suffix)?;
-		config.add(domain.to_owned(), domain_config);
+		edit_setting("Identity", &mut domain_config.identity)?;
+		edit_domain_config(&mut domain_config)?;
+		config.add(domain.to_owned(), domain_config)?;
 	} else {
 		let domain = get_domain()?;
-		println!("Domain: {}", domain);
-		let domain_config = config.for_domain(&domain);
+		let resolved = config.resolve(domain).into_owned();
+		if resolved == domain.as_str() {
+			println!("Domain: {}", domain);
+		} else {
+			println!("Domain: {} (alias of {})", domain, &resolved);
+		}
+		let domain_config = config.for_domain_as(domain, identity)?;
 		debug!("domain config: {:?}", &domain_config);
 		domain_config.as_ref().print();
-		if let config::Defaulted::Default(_) = domain_config {
-			println!("* new domain: {}", &domain);
+		match &domain_config {
+			config::Defaulted::Default(_) => println!("* new domain: {}", &domain),
+			config::Defaulted::Inherited { from, .. } => println!("* inherited from: {}", from),
+			config::Defaulted::Explicit(_) => (),
+		}
+		if let Some(duplicates) = config.duplicates().get(resolved.as_str()) {
+			eprintln!("WARNING: {} has duplicate configs ({}), using the first. Keep one with --keep-duplicate.", &resolved, duplicates.join(", "));
+		}
+		let domain_identity = domain_config.as_ref().identity.as_deref();
+		let prompt = match domain_identity {
+			Some(name) => format!("Password ({}): ", name),
+			None => "Password: ".to_owned(),
+		};
+		let password = prompt_secret(&prompt)?;
+		println!("Fingerprint: {}", Fingerprint::new(Password(&password)));
+		if config.check_password(domain_identity, Password(&password)) == Some(false) {
+			eprintln!("WARNING: this doesn't match your remembered master password");
 		}
-		let password = rpassword::prompt_password("Password: ").unwrap();
-		let generated = password::generate(Domain(domain), Password(&password), domain_config.underlying());
+		let generated = password::generate(Domain(&resolved), Password(&password), domain_config.as_ref())
+			.with_context(|| format!("Generating password for {}", domain))?;
 
 		// finalize early in this branch, since we wait below and an impatient user may ctrl+c
 		finalize(&mut config)?;
@@ -87,12 +221,12 @@ pub fn main() -> Result<()> {
 				let suffix = "";
 
 				println!("(copied to your clipboard{})", suffix);
-				set.text(&generated)?;
+				set.text(generated.as_str())?;
 			},
 			Result::Err(e) => {
 				error!("Clipboard failed: {:?}", e);
 				rpassword::prompt_password("Press return to print password ...").unwrap();
-				println!("{}", &generated);
+				println!("{}", generated.as_str());
 			}
 		}
 	}
@@ -100,6 +234,138 @@ pub fn main() -> Result<()> {
 	finalize(&mut config)
 }
 
+fn migrate_master(config: &mut Config, identity: Option<&str>) -> Result<()> {
+	let old = prompt_secret("Old master password: ")?;
+	if config.check_password(identity, Password(&old)) == Some(false) {
+		bail!("This doesn't match your remembered master password");
+	}
+	let new = prompt_secret("New master password: ")?;
+
+	if let Some(migration) = config.migration() {
+		if migration.identity.as_deref() == identity && migration.verifier.verify(Password(&new)) {
+			println!("Resuming migration");
+		} else if confirm("A different migration is in progress. Abandon it?")? {
+			config.abandon_migration();
+		} else {
+			bail!("Migration cancelled");
+		}
+	}
+
+	if config.migration().is_none() {
+		if prompt_secret("Confirm new master password: ")? != new {
+			bail!("Passwords don't match");
+		}
+		let mut salt = [0u8; 16];
+		rand::rng().fill_bytes(&mut salt);
+		config.start_migration(identity, Verifier::new(Password(&new), salt, now()?))?;
+		finalize(config)?;
+	}
+	println!("New fingerprint: {}", Fingerprint::new(Password(&new)));
+
+	let domains = config.unmigrated_domains()?;
+	for (index, domain) in domains.iter().enumerate() {
+		let passwords = config.migrate_password(domain, Password(&old), Password(&new))?;
+		println!("\n[{}/{}] {}", index + 1, domains.len(), domain);
+		println!("Old: {}", passwords.old.as_str());
+		println!("New: {}", passwords.new.as_str());
+		let response = rprompt::prompt_reply("Changed? [y]es, [s]kip, [q]uit: ")?;
+		match response.to_ascii_lowercase().as_str() {
+			"y" | "yes" => {
+				config.mark_migrated(domain)?;
+				// save after every domain, so that an interrupted migration can be resumed
+				finalize(config)?;
+			},
+			"q" | "quit" => {
+				println!("Run `passe migrate-master` again to resume");
+				return Ok(());
+			},
+			_ => (),
+		}
+	}
+
+	let remaining = config.unmigrated_domains()?.len();
+	if remaining == 0 {
+		config.finish_migration()?;
+		println!("Migration complete; your new master password is now remembered");
+	} else {
+		println!("{} domains skipped; run `passe migrate-master` again to resume", remaining);
+	}
+	Ok(())
+}
+
+fn set_sync_passphrase(agent: &Agent, config: &mut Config) -> Result<()> {
+	let vault: Option<Vault> = authed_request::<(), _>(agent, config, "vault", None)?;
+	let passphrase = prompt_secret("Sync passphrase: ")?;
+	let key = match vault {
+		Some(vault) => SyncKey::for_sealed(&passphrase, &vault.sealed)?,
+		None => {
+			let confirmation = prompt_secret("Confirm sync passphrase: ")?;
+			if passphrase != confirmation {
+				bail!("Passphrases don't match");
+			}
+			if !config.uses_encrypted_sync() {
+				// the server's data is replaced by the first vault, so it must include everything
+				info!("Syncing before encrypting ...");
+				config.save_user()?;
+				plaintext_sync(agent, config, false)?;
+			}
+			let mut salt = [0u8; SALT_LENGTH];
+			rand::rng().fill_bytes(&mut salt);
+			SyncKey::new(&passphrase, salt)?
+		},
+	};
+	config.set_sync_key(key);
+	Ok(())
+}
+
+fn plaintext_sync(agent: &Agent, config: &mut Config, full: bool) -> Result<()> {
+	let request = config.sync_request(full);
+	let sync_result: SyncResponse = authed_request(agent, config, "sync", Some(&request))?;
+	*config = reload_user(config)?;
+	config.update_after_sync(sync_result)
+}
+
+const VAULT_ATTEMPTS: usize = 3;
+
+// The server can't merge encrypted changes, so we merge them into the
+// vault ourselves, and try again if another device uploads in the meantime
+fn vault_sync(agent: &Agent, config: &mut Config, full: bool) -> Result<()> {
+	for _ in 0..VAULT_ATTEMPTS {
+		let vault: Option<Vault> = authed_request::<(), _>(agent, config, "vault", None)?;
+		let mut nonce = [0u8; NONCE_LENGTH];
+		rand::rng().fill_bytes(&mut nonce);
+		let sync = config.vault_sync(vault.as_ref(), full, nonce)?;
+		let result: VaultResponse = authed_request(agent, config, "vault", Some(&sync.upload))?;
+		if result.accepted {
+			*config = reload_user(config)?;
+			return config.update_after_sync(sync.response);
+		}
+		info!("The vault changed during sync, retrying");
+	}
+	bail!("The vault kept changing during sync, please try again")
+}
+
+// Other `passe` processes may have saved changes while a sync was in flight, so its
+// response is applied to the latest user.json. Acknowledged changes are matched by id,
+// so any made after the request was sent are kept.
+fn reload_user(config: &Config) -> Result<Config> {
+	let mut latest = Config::load_user()?;
+	// we may have logged in during the sync
+	if let Some(auth) = config.get() {
+		latest.set(auth.clone());
+	}
+	Ok(latest)
+}
+
+// Secrets are wiped from memory once dropped
+fn prompt_secret(prompt: &str) -> Result<Zeroizing<String>> {
+	Ok(Zeroizing::new(rpassword::prompt_password(prompt)?))
+}
+
+fn now() -> Result<u64> {
+	Ok(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs())
+}
+
 fn finalize(config: &mut Config) -> Result<()> {
 	config.save_user()
 }
@@ -111,7 +377,7 @@ fn make_url(suffix: &'static str) -> String {
 
 fn login(agent: &Agent, credentials: &LoginRequest) -> Result<Authentication> {
 	Ok(agent.post(&make_url("login"))
-		.send_json(&credentials)?
+		.send_json(credentials)?
 		.body_mut()
 		.read_json::<Authentication>()?)
 }
@@ -152,7 +418,7 @@ fn authed_request<Data: Serialize, Response: DeserializeOwned>(agent: &Agent, au
 		Some(result) => result,
 		None => {
 			let creds = auth_manager.ask_credentials()?;
-			let auth = login(&agent, &creds)?;
+			let auth = login(agent, &creds)?;
 			auth_manager.set(auth.clone());
 			do_req(&auth)?.ok_or_else(||anyhow!("Unauthorized"))
 		}
@@ -162,7 +428,7 @@ fn authed_request<Data: Serialize, Response: DeserializeOwned>(agent: &Agent, au
 trait AuthManager {
 	fn get(&self) -> Option<&Authentication>;
 	fn ask_credentials(&self) -> Result<LoginRequest>;
-	fn set(&mut self, auth: Authentication) -> ();
+	fn set(&mut self, auth: Authentication);
 }
 
 impl AuthManager for Config {
@@ -176,7 +442,7 @@ impl AuthManager for Config {
 			Some(u) => Cow::Owned(format!("[{}]", u)),
 			None => Cow::Borrowed(""),
 		};
-		let mut user = rprompt::prompt_reply(&format!("User: {}", prompt_suffix))?;
+		let mut user = rprompt::prompt_reply(format!("User: {}", prompt_suffix))?;
 		if user.is_empty() {
 			user = existing.ok_or_else(||anyhow!("user required"))?.to_owned();
 		}
@@ -184,7 +450,7 @@ impl AuthManager for Config {
 		Ok(LoginRequest { user, password })
 	}
 
-	fn set(&mut self, auth: Authentication) -> () {
+	fn set(&mut self, auth: Authentication) {
 		self.data.authentication = Some(auth);
 		self.dirty = true;
 	}
@@ -202,3 +468,57 @@ fn edit_setting(desc: &str, config: &mut Option<String>) -> Result<()> {
 	}
 	Ok(())
 }
+
+fn edit_parsed<T: FromStr + Display>(desc: &str, config: &mut T) -> Result<()> where T::Err: Display {
+	let response = rprompt::prompt_reply(format!("{}: [{}] ", desc, config))?;
+	if !response.is_empty() {
+		*config = response.parse().map_err(|e| anyhow!("{}", e))?;
+	}
+	Ok(())
+}
+
+// settings shared by domains and the defaults
+fn edit_domain_config(domain_config: &mut DomainConfig) -> Result<()> {
+	edit_parsed(&format!("Length ({}-{})", MIN_LENGTH, MAX_LENGTH), &mut domain_config.length)?;
+	edit_setting("Suffix", &mut domain_config.suffix)?;
+	edit_setting("Post-suffix", &mut domain_config.post_suffix)?;
+	edit_parsed(&format!("Algorithm ({})", Algorithm::ALL.map(|alg| alg.name()).join(", ")), &mut domain_config.algorithm)?;
+	if confirm("Edit password policy?")? {
+		edit_policy(&mut domain_config.policy)?;
+	}
+	Ok(())
+}
+
+fn print_conflict(name: &str, local: Option<&DomainConfig>, remote: Option<&DomainConfig>, resolve_arg: &str) {
+	println!("\nConflict: {} changed on another device", name);
+	for (side, config) in [("Local", local), ("Remote", remote)] {
+		println!("{}:", side);
+		match config {
+			Some(config) => config.print(),
+			None => println!("(deleted)"),
+		}
+	}
+	println!("Use --resolve local|remote {} to keep one", resolve_arg);
+}
+
+fn confirm(desc: &str) -> Result<bool> {
+	let response = rprompt::prompt_reply(format!("{} [y/N] ", desc))?;
+	Ok(response.eq_ignore_ascii_case("y"))
+}
+
+fn edit_policy(policy: &mut Policy) -> Result<()> {
+	let desc = format!("Required ({})", CharClass::ALL.map(|c| c.name()).join(", "));
+	let prompt = if policy.required.is_empty() {
+		format!("{}: ", desc)
+	} else {
+		format!("{}: [{}] ", desc, CharClass::format_list(&policy.required))
+	};
+	let response = rprompt::prompt_reply(prompt)?;
+	if !response.is_empty() {
+		policy.required = CharClass::parse_list(&response)?;
+	}
+	edit_setting("Alphabet", &mut policy.alphabet)?;
+	edit_setting("Allowed characters", &mut policy.allowed)?;
+	edit_setting("Forbidden characters", &mut policy.forbidden)?;
+	Ok(())
+}
@@ -1,4 +1,5 @@
 use serde::{Serialize, Deserialize};
+use zeroize::Zeroize;
 
 #[derive(Serialize, Deserialize)]
 pub struct LoginRequest {
@@ -6,6 +7,12 @@ pub struct LoginRequest {
 	pub password: String,
 }
 
+impl Drop for LoginRequest {
+	fn drop(&mut self) {
+		self.password.zeroize();
+	}
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Authentication {
 	pub user: String,
@@ -2,37 +2,198 @@ use log::*;
 use anyhow::*;
 use serde::{Serialize, Deserialize};
 use std::{fs, collections::BTreeMap, path::PathBuf, ops::Deref};
+use std::borrow::Cow;
 use std::cmp::Ordering;
 use std::collections::BTreeSet;
+use std::fmt;
+use zeroize::Zeroizing;
 
 use crate::auth::Authentication;
-use crate::domain_extractor::DomainExtractor;
+use crate::encryption::{SyncKey, NONCE_LENGTH};
+use crate::domain_extractor::{DomainExtractor, normalize_domain};
+use crate::password::{self, Algorithm, Domain, Password};
+use crate::schema::{self, Loaded, Schema};
+use crate::sync::{self, ChangeIds, Revisions, Settings, SyncRequest, SyncResponse, Vault, VaultSync, VaultUpload, merge_identities, newest_verifier};
+use crate::verifier::Verifier;
+use crate::policy::Policy;
+
+pub const MIN_LENGTH: usize = 4;
+pub const MAX_LENGTH: usize = 128;
+
+fn is_zero<T: Default + PartialEq>(n: &T) -> bool {
+	*n == T::default()
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Rotation {
+	pub rotation: u32,
+
+	// seconds since the unix epoch
+	pub timestamp: u64,
+
+	#[serde(flatten)]
+	pub extra: Extra,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct DomainConfig {
 	pub length: usize,
 
 	#[serde(default)]
 	#[serde(skip_serializing_if = "Option::is_none")]
 	pub suffix: Option<String>, // suffix for password
-	// pub post_suffix: Option<String>, // suffix for generated password
 
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub post_suffix: Option<String>, // suffix for generated password
+
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Algorithm::is_default")]
+	pub algorithm: Algorithm,
+
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Policy::is_empty")]
+	pub policy: Policy,
+
+	// incremented to change the password without changing anything else
+	#[serde(default)]
+	#[serde(skip_serializing_if = "is_zero")]
+	pub rotation: u32,
+
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub rotations: Vec<Rotation>,
+
+	// the name of the master password identity used for this domain
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub identity: Option<String>,
+
+	// the migration (see `Migration::id`) for which this domain's password has been changed
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub migrated: Option<u64>,
+
+	// the domain name used to generate the password, when it differs
+	// from the name this config is stored under
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub generate_as: Option<String>,
+
+	// the server's revision when this config was last changed (0 if it's never been synced)
+	#[serde(default)]
+	#[serde(skip_serializing_if = "is_zero")]
+	pub revision: u64,
+
+	// `hint` in files is upgraded by CONFIG_SCHEMA, but older servers may still send it
 	#[serde(alias = "hint")]
 	#[serde(default)]
 	#[serde(skip_serializing_if = "Option::is_none")]
 	pub note: Option<String>,
+
+	#[serde(flatten)]
+	pub extra: Extra,
+}
+
+// suffixes are mixed into the generated password, so they're kept out of logs
+impl fmt::Debug for DomainConfig {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let redacted = |value: &Option<String>| value.as_ref().map(|_| "<redacted>");
+		f.debug_struct("DomainConfig")
+			.field("length", &self.length)
+			.field("suffix", &redacted(&self.suffix))
+			.field("post_suffix", &redacted(&self.post_suffix))
+			.field("algorithm", &self.algorithm)
+			.field("policy", &self.policy)
+			.field("rotation", &self.rotation)
+			.field("rotations", &self.rotations)
+			.field("identity", &self.identity)
+			.field("migrated", &self.migrated)
+			.field("generate_as", &self.generate_as)
+			.field("revision", &self.revision)
+			.field("note", &self.note)
+			.field("extra", &self.extra)
+			.finish()
+	}
 }
 
 impl DomainConfig {
 	pub fn print(&self) {
+		if let Some(identity) = &self.identity {
+			println!("Identity: {}", identity);
+		}
+
+		if let Some(generate_as) = &self.generate_as {
+			println!("Generated as: {}", generate_as);
+		}
+
+		if !self.algorithm.is_default() {
+			println!("Algorithm: {}", self.algorithm);
+		}
+
 		if let Some(suffix) = &self.suffix {
 			println!("Suffix: {}", suffix);
 		}
 
+		if let Some(post_suffix) = &self.post_suffix {
+			println!("Post-suffix: {}", post_suffix);
+		}
+
+		self.policy.print();
+
+		if self.rotation > 0 {
+			println!("Rotation: {}", self.rotation);
+		}
+
 		if let Some(note) = &self.note {
 			println!("Note: {}", note);
 		}
 	}
+
+	pub fn validate(&self) -> Result<()> {
+		self.validate_keeping_length(None)
+	}
+
+	// Like `validate`, but accepts `kept_length` even if it's out of range, so that
+	// configs stored before lengths were validated can still be changed
+	fn validate_keeping_length(&self, kept_length: Option<usize>) -> Result<()> {
+		if Some(self.length) != kept_length && (self.length < MIN_LENGTH || self.length > MAX_LENGTH) {
+			bail!("Length must be between {} and {}, not {}", MIN_LENGTH, MAX_LENGTH, self.length);
+		}
+		if let Some(post_suffix) = &self.post_suffix {
+			if post_suffix.chars().count() >= self.length {
+				bail!("Post-suffix must be shorter than the length ({})", self.length);
+			}
+			if let Some(forbidden) = post_suffix.chars().find(|ch| self.policy.forbidden.as_deref().unwrap_or("").contains(*ch)) {
+				bail!("Post-suffix contains forbidden character {:?}", forbidden);
+			}
+		}
+		Ok(())
+	}
+
+	// The settings which a subdomain inherits. State belonging
+	// to this domain's own password is not inherited.
+	fn for_subdomain(&self) -> DomainConfig {
+		DomainConfig {
+			generate_as: None,
+			rotation: 0,
+			rotations: Vec::new(),
+			migrated: None,
+			revision: 0,
+			..self.clone()
+		}
+	}
+
+	// Legacy output used to be silently truncated to a single encoded digest.
+	// Returns the length which was actually in effect, if that's shorter than `length`.
+	fn truncated_length(&self) -> Option<usize> {
+		let limit = self.algorithm.encoded_length();
+		if self.policy.is_empty() && self.length > limit {
+			Some(limit)
+		} else {
+			None
+		}
+	}
 }
 
 impl Default for DomainConfig {
@@ -40,7 +201,17 @@ impl Default for DomainConfig {
 		Self {
 			length: 10,
 			suffix: Default::default(),
-			note: Default::default()
+			post_suffix: Default::default(),
+			algorithm: Default::default(),
+			policy: Default::default(),
+			rotation: Default::default(),
+			rotations: Default::default(),
+			identity: Default::default(),
+			migrated: Default::default(),
+			generate_as: Default::default(),
+			revision: Default::default(),
+			note: Default::default(),
+			extra: Default::default(),
 		}
 	}
 }
@@ -48,19 +219,136 @@ impl Default for DomainConfig {
 impl DomainConfig {
 	#![allow(dead_code)]
 	pub fn with_length(self, length: usize) -> Self {
-		Self {
-			length,
-			suffix: self.suffix,
-			note: self.note,
-		}
+		Self { length, ..self }
+	}
+
+	pub fn with_algorithm(self, algorithm: Algorithm) -> Self {
+		Self { algorithm, ..self }
+	}
+
+	pub fn with_policy(self, policy: Policy) -> Self {
+		Self { policy, ..self }
 	}
 }
 
 pub type Changes = BTreeMap<String, Change<DomainConfig>>;
 pub type Domains = BTreeMap<String, DomainConfig>;
+pub type Identities = BTreeMap<String, Identity>;
+
+/// Fields written by a newer version, which are kept so that
+/// they survive being loaded and saved by this version
+pub type Extra = BTreeMap<String, serde_json::Value>;
+
+/// The format of `ConfigFile`, used for the CLI's `user.json`,
+/// the web app's local storage, the server's `user-<name>.json` and encrypted vaults
+pub const CONFIG_SCHEMA: Schema = Schema {
+	name: "user config",
+	upgrades: &[rename_hints, truncated_lengths],
+};
+
+pub const CONFIG_VERSION: u32 = CONFIG_SCHEMA.version();
+
+// the version whose upgrade clamps truncated lengths
+const CLAMPED_LENGTHS_VERSION: u32 = 2;
+
+// v0 -> v1: domain notes used to be called `hint`
+fn rename_hints(value: &mut serde_json::Value) -> Result<()> {
+	fn rename(domain_config: Option<&mut serde_json::Value>) {
+		if let Some(object) = domain_config.and_then(|value| value.as_object_mut()) {
+			if let Some(hint) = object.remove("hint") {
+				object.entry("note").or_insert(hint);
+			}
+		}
+	}
+	rename(value.get_mut("defaults"));
+	for key in ["domains", "changes", "identities"] {
+		let Some(entries) = value.get_mut(key).and_then(|entries| entries.as_object_mut()) else { continue };
+		for entry in entries.values_mut() {
+			match key {
+				"changes" => rename(entry.get_mut("Set")),
+				"identities" => rename(entry.get_mut("defaults")),
+				_ => rename(Some(entry)),
+			}
+		}
+	}
+	Ok(())
+}
+
+// v1 -> v2: lengths beyond a single encoded digest used to be truncated. Telling which
+// ones were needs typed configs, so `Config::load` clamps them for files older than v2.
+fn truncated_lengths(_value: &mut serde_json::Value) -> Result<()> {
+	Ok(())
+}
+
+// alias -> canonical domain
+pub type Aliases = BTreeMap<String, String>;
+pub type AliasChanges = BTreeMap<String, Change<String>>;
+
+/// Mobile app identifiers whose package name doesn't match their web domain
+pub type Apps = BTreeMap<String, String>;
+
+// guards against alias cycles
+const MAX_ALIAS_DEPTH: usize = 10;
+
+/// A named master password, e.g. "work" or "personal".
+/// The top-level `defaults` and `verifier` belong to the unnamed default identity.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Identity {
+	#[serde(default)]
+	pub defaults: DomainConfig,
+
+	// seconds since the unix epoch when `defaults` were last changed.
+	// When syncing, the newest defaults win.
+	#[serde(default)]
+	#[serde(skip_serializing_if = "is_zero")]
+	pub defaults_updated: u64,
+
+	#[serde(default)]
+	pub verifier: Option<Verifier>,
+
+	#[serde(flatten)]
+	pub extra: Extra,
+}
+
+/// An in-progress change of master password. Domains are marked
+/// as they're migrated, so that the process can be resumed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Migration {
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub identity: Option<String>,
+
+	// verifier for the new master password
+	pub verifier: Verifier,
+
+	#[serde(flatten)]
+	pub extra: Extra,
+}
+
+impl Migration {
+	pub fn id(&self) -> u64 {
+		self.verifier.created
+	}
+}
+
+/// The old and new passwords for a single domain
+#[derive(Clone, PartialEq, Eq)]
+pub struct MigratedPassword {
+	pub old: Zeroizing<String>,
+	pub new: Zeroizing<String>,
+}
+
+impl fmt::Debug for MigratedPassword {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str("MigratedPassword(<redacted>)")
+	}
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ConfigFile {
+	#[serde(default)]
+	pub version: u32,
+
 	#[serde(default)]
 	pub authentication: Option<Authentication>,
 
@@ -72,17 +360,128 @@ pub struct ConfigFile {
 
 	#[serde(default)]
 	pub changes: Changes,
+
+	#[serde(default)]
+	pub verifier: Option<Verifier>,
+
+	#[serde(default)]
+	pub identities: Identities,
+
+	#[serde(default)]
+	pub migration: Option<Migration>,
+
+	#[serde(default)]
+	pub aliases: Aliases,
+
+	#[serde(default)]
+	pub alias_changes: AliasChanges,
+
+	#[serde(default)]
+	#[serde(skip_serializing_if = "BTreeMap::is_empty")]
+	pub apps: Apps,
+
+	// domains whose local change conflicts with a change from another device.
+	// They aren't synced until the conflict is resolved.
+	#[serde(default)]
+	#[serde(skip_serializing_if = "BTreeSet::is_empty")]
+	pub conflicts: BTreeSet<String>,
+
+	// the latest revision of `domains`, incremented by the server for each change.
+	// For clients, the revision as of the last sync.
+	#[serde(default)]
+	#[serde(skip_serializing_if = "is_zero")]
+	pub revision: u64,
+
+	// (server only) the revision at which each deleted domain was deleted,
+	// so that incremental syncs can include deletions
+	#[serde(default)]
+	#[serde(skip_serializing_if = "BTreeMap::is_empty")]
+	pub deleted: Revisions,
+
+	// the server's revision when `defaults` and `apps` were last changed
+	#[serde(default)]
+	#[serde(skip_serializing_if = "is_zero")]
+	pub settings_revision: u64,
+
+	// (client only) the `settings_revision` which local changes to settings were based on
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub settings_base_revision: Option<u64>,
+
+	// the server's settings, when they conflict with local changes.
+	// Local settings aren't synced until the conflict is resolved.
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub settings_conflict: Option<Settings>,
+
+	// ids of the entries in `changes` and `alias_changes`, and of changed settings
+	#[serde(default)]
+	#[serde(skip_serializing_if = "ChangeIds::is_empty")]
+	pub change_ids: ChangeIds,
+
+	#[serde(default)]
+	#[serde(skip_serializing_if = "is_zero")]
+	pub last_change_id: u64,
+
+	// (client only) the revision each entry in `changes` was based on, so that
+	// the server can tell whether another device has changed it since
+	#[serde(default)]
+	#[serde(skip_serializing_if = "BTreeMap::is_empty")]
+	pub base_revisions: Revisions,
+
+	// (client only) the key for encrypted sync. When set, the server stores
+	// an encrypted vault instead, which the client merges its changes into.
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub sync_key: Option<SyncKey>,
+
+	#[serde(flatten)]
+	pub extra: Extra,
 }
 
 impl Default for ConfigFile {
 	fn default() -> Self {
 		Self {
+			version: CONFIG_VERSION,
 			authentication: Default::default(),
 			defaults: Default::default(),
 			domains: Default::default(),
 			changes: Default::default(),
+			verifier: Default::default(),
+			identities: Default::default(),
+			migration: Default::default(),
+			aliases: Default::default(),
+			alias_changes: Default::default(),
+			apps: Default::default(),
+			conflicts: Default::default(),
+			revision: Default::default(),
+			deleted: Default::default(),
+			settings_revision: Default::default(),
+			settings_base_revision: Default::default(),
+			settings_conflict: Default::default(),
+			change_ids: Default::default(),
+			base_revisions: Default::default(),
+			last_change_id: Default::default(),
+			sync_key: Default::default(),
+			extra: Default::default(),
+		}
+	}
+}
+
+impl ConfigFile {
+	pub fn settings(&self) -> Settings {
+		Settings {
+			defaults: self.defaults.clone(),
+			apps: self.apps.clone(),
+			revision: self.settings_revision,
 		}
 	}
+
+	pub fn set_settings(&mut self, settings: Settings) {
+		self.defaults = settings.defaults;
+		self.apps = settings.apps;
+		self.settings_revision = settings.revision;
+	}
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -115,9 +514,62 @@ pub enum Change<T> {
 	Set(T),
 }
 
+/// A local change which hasn't been synced yet
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+pub struct PendingChange<'a> {
+	pub name: &'a str,
+	pub kind: PendingKind,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PendingKind {
+	Added,
+	Updated,
+	Deleted,
+	Conflicted,
+	Aliased,
+	Unaliased,
+}
+
+impl fmt::Display for PendingChange<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let kind = match self.kind {
+			PendingKind::Added => "added",
+			PendingKind::Updated => "updated",
+			PendingKind::Deleted => "deleted",
+			PendingKind::Conflicted => "conflicted",
+			PendingKind::Aliased => "aliased",
+			PendingKind::Unaliased => "unaliased",
+		};
+		write!(f, "{}: {}", kind, self.name)
+	}
+}
+
+/// A local change which was rejected by the server, because
+/// another device changed the same domain since we last synced
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+pub struct Conflict<'a> {
+	pub domain: &'a str,
+
+	// `None` if deleted
+	pub local: Option<&'a DomainConfig>,
+	pub remote: Option<&'a DomainConfig>,
+}
+
+/// Which side of a `Conflict` to keep
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Resolution {
+	Local,
+	Remote,
+}
+
 #[derive(Debug, Clone)]
 pub enum Defaulted<T> {
 	Explicit(T),
+	// stored for a parent domain or wildcard, under the key `from`
+	Inherited { from: String, value: T },
 	Default(T),
 }
 
@@ -125,6 +577,7 @@ impl<T> AsRef<T> for Defaulted<T> {
 	fn as_ref(&self) -> &T {
 		match &self {
 			Defaulted::Explicit(t) => t,
+			Defaulted::Inherited { value, .. } => value,
 			Defaulted::Default(t) => t,
 		}
 	}
@@ -134,30 +587,41 @@ impl<T> Defaulted<T> {
 	pub fn explicit(self) -> Option<T> {
 		match self {
 			Defaulted::Explicit(t) => Some(t),
-			Defaulted::Default(_) => None,
+			Defaulted::Inherited { .. } | Defaulted::Default(_) => None,
+		}
+	}
+
+	pub fn inherited_from(&self) -> Option<&str> {
+		match self {
+			Defaulted::Inherited { from, .. } => Some(from),
+			Defaulted::Explicit(_) | Defaulted::Default(_) => None,
 		}
 	}
 
 	pub fn underlying(self) -> T {
 		match self {
 			Defaulted::Explicit(t) => t,
+			Defaulted::Inherited { value, .. } => value,
 			Defaulted::Default(t) => t,
 		}
 	}
+
+	pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Defaulted<U> {
+		match self {
+			Defaulted::Explicit(t) => Defaulted::Explicit(f(t)),
+			Defaulted::Inherited { from, value } => Defaulted::Inherited { from, value: f(value) },
+			Defaulted::Default(t) => Defaulted::Default(f(t)),
+		}
+	}
 }
 
+#[derive(Default)]
 pub struct Config {
 	pub data: ConfigFile,
 	pub dirty: bool,
 	extractor: DomainExtractor,
 }
 
-impl Default for Config {
-	fn default() -> Self {
-		Self { data: Default::default(), dirty: false, extractor: Default::default() }
-	}
-}
-
 impl Deref for Config {
 	type Target = ConfigFile;
 
@@ -172,9 +636,133 @@ impl Config {
 	}
 	
 	pub fn deserialize(s: &str) -> Result<Config> {
-		let data = serde_json::from_str::<ConfigFile>(s)
-			.context("Deserializing user config")?;
-		Ok(Self { data, dirty: false, extractor: Default::default() })
+		Ok(Self::load(s)?.data)
+	}
+
+	fn load(s: &str) -> Result<Loaded<Config>> {
+		let Loaded { data, upgraded_from } = CONFIG_SCHEMA.load::<ConfigFile>(s)?;
+		let mut config = Self { data, dirty: upgraded_from.is_some(), extractor: Default::default() };
+		config.migrate();
+		if upgraded_from.is_some_and(|version| version < CLAMPED_LENGTHS_VERSION) {
+			config.clamp_truncated_lengths();
+		}
+		Ok(Loaded { data: config, upgraded_from })
+	}
+
+	// Upgrade data written by older versions, which may also arrive by syncing.
+	// Entries aren't validated, so that a legacy config can never prevent loading.
+	fn migrate(&mut self) {
+		self.normalize_domains()
+	}
+
+	// Domains used to be stored exactly as entered. Move entries which differ only
+	// by case or encoding under their normalized name, generating the password from
+	// the original name so that it doesn't change. Entries which would generate
+	// different passwords are kept until the user picks one (see `duplicates`).
+	fn normalize_domains(&mut self) {
+		for (normalized, domains) in self.unnormalized_groups() {
+			let first = self.generated_as(&domains[0]);
+			if domains.iter().any(|domain| self.generated_as(domain) != first) {
+				continue;
+			}
+			// they're all equivalent, so prefer an entry which is already normalized
+			let keep = if domains.contains(&normalized) { &normalized } else { &domains[0] };
+			for domain in domains.iter().filter(|domain| *domain != keep) {
+				warn!("Discarding config for {}, which is identical to {}", domain, keep);
+				self.remove_exact(domain);
+			}
+			if *keep != normalized {
+				self.normalize_entry(keep, normalized.clone());
+			}
+		}
+	}
+
+	// Stored domains grouped by their normalized name, for names with
+	// entries which aren't normalized
+	fn unnormalized_groups(&self) -> BTreeMap<String, Vec<String>> {
+		let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+		for domain in self.domain_list() {
+			if self.stored(domain).is_some() {
+				groups.entry(normalize_domain(domain).into_owned()).or_default().push(domain.to_owned());
+			}
+		}
+		groups.retain(|normalized, domains| domains.iter().any(|domain| domain != normalized));
+		groups
+	}
+
+	// A stored domain's config as it would be under its normalized name,
+	// which generates the same password
+	fn generated_as(&self, domain: &str) -> DomainConfig {
+		let domain_config = self.stored(domain).expect("stored domain").clone();
+		DomainConfig {
+			generate_as: domain_config.generate_as.clone().or_else(|| Some(domain.to_owned())),
+			revision: 0,
+			..domain_config
+		}
+	}
+
+	fn normalize_entry(&mut self, domain: &str, normalized: String) {
+		warn!("Renaming {} to {}", domain, &normalized);
+		let domain_config = DomainConfig { revision: self.stored(domain).expect("stored domain").revision, ..self.generated_as(domain) };
+		self.remove_exact(domain);
+		self.set_change(normalized, Change::Set(domain_config));
+	}
+
+	/// Stored domains which differ only by case or encoding but would generate different
+	/// passwords, by normalized name. Until one is picked with `keep_duplicate`, lookups
+	/// use the first of them (or the one which is already normalized).
+	pub fn duplicates(&self) -> BTreeMap<String, Vec<String>> {
+		let mut groups = self.unnormalized_groups();
+		groups.retain(|_, domains| domains.len() > 1);
+		groups
+	}
+
+	/// Resolve a duplicate by keeping `domain` (exactly as stored) for its normalized name,
+	/// along with its password, and removing the others
+	pub fn keep_duplicate(&mut self, domain: &str) -> Result<()> {
+		let normalized = normalize_domain(domain).into_owned();
+		let Some(domains) = self.duplicates().remove(&normalized) else {
+			bail!("{} has no duplicates", &normalized);
+		};
+		if !domains.iter().any(|duplicate| duplicate == domain) {
+			bail!("{} isn't one of the duplicates of {}: {}", domain, &normalized, domains.join(", "));
+		}
+		for duplicate in domains.iter().filter(|duplicate| *duplicate != domain) {
+			info!("Removing duplicate {}", duplicate);
+			self.remove_exact(duplicate);
+		}
+		if domain != normalized {
+			self.normalize_entry(domain, normalized);
+		}
+		Ok(())
+	}
+
+	fn check_duplicates(&self, domain: &str) -> Result<()> {
+		if let Some(domains) = self.duplicates().get(domain) {
+			bail!("{} has duplicate configs ({}), please keep one of them first", domain, domains.join(", "));
+		}
+		Ok(())
+	}
+
+	// Lengths beyond a single encoded digest used to be truncated. Now that they're not,
+	// store the effective length so that existing passwords don't change.
+	fn clamp_truncated_lengths(&mut self) {
+		if let Some(length) = self.data.defaults.truncated_length() {
+			warn!("Default length {} was previously truncated to {}", self.data.defaults.length, length);
+			self.data.defaults.length = length;
+			self.dirty = true;
+		}
+
+		let truncated: Vec<(String, DomainConfig)> = self.domain_list()
+			.filter_map(|domain| self.for_domain(domain).explicit().and_then(|config| {
+				config.truncated_length().map(|length| (domain.to_owned(), config.into_owned().with_length(length)))
+			}))
+			.collect();
+		for (domain, config) in truncated {
+			warn!("Length for {} was previously truncated to {}", &domain, config.length);
+			self.set_change(domain, Change::Set(config));
+			self.dirty = true;
+		}
 	}
 
 	pub fn load_user() -> Result<Config> {
@@ -182,7 +770,15 @@ impl Config {
 		let result = if path.exists() {
 			info!("Loading {:?}", &path);
 			let contents = fs::read_to_string(&path)?;
-			Self::deserialize(&contents).with_context(|| format!("Processing {:?}", &path))?
+			let loaded = Self::load(&contents).with_context(|| format!("Processing {:?}", &path))?;
+			let mut config = loaded.data;
+			if let Some(version) = loaded.upgraded_from {
+				let backup = path.with_file_name(schema::backup_name("user.json", version));
+				warn!("Upgrading {:?} from version {}, saving a backup to {:?}", &path, version, &backup);
+				fs::write(&backup, &contents)?;
+				config.save_user()?;
+			}
+			config
 		} else {
 			debug!("No config exists at {:?}", &path);
 			Default::default()
@@ -194,7 +790,7 @@ impl Config {
 		if self.dirty {
 			let path = Self::user_path();
 			info!("Storing {}", &path.to_string_lossy());
-			fs::write(&Self::user_path(), serde_json::to_string_pretty(&self.data)?)?;
+			fs::write(Self::user_path(), serde_json::to_string_pretty(&self.data)?)?;
 			self.update_after_save();
 		}
 		Ok(())
@@ -220,6 +816,141 @@ impl Config {
 		&self.data.changes
 	}
 
+	pub fn sync_request(&self, full: bool) -> SyncRequest {
+		let mut changes = if full {
+			self.full_changes()
+		} else {
+			self.data.changes.clone()
+		};
+		changes.retain(|domain, _| !self.data.conflicts.contains(domain));
+		let base_revisions = changes.keys()
+			.map(|domain| (domain.clone(), self.base_revision(domain)))
+			.collect();
+		let alias_changes = if full {
+			let mut result: AliasChanges = self.data.aliases.iter()
+				.map(|(k,v)| (k.clone(), Change::Set(v.clone())))
+				.collect();
+			result.append(&mut self.data.alias_changes.clone());
+			result
+		} else {
+			self.data.alias_changes.clone()
+		};
+		let ids = &self.data.change_ids;
+		let change_ids = ChangeIds {
+			domains: self.data.changes.keys()
+				.filter(|domain| changes.contains_key(*domain))
+				.map(|domain| (domain.clone(), ids.domain(domain)))
+				.collect(),
+			aliases: self.data.alias_changes.keys()
+				.map(|alias| (alias.clone(), ids.alias(alias)))
+				.collect(),
+			settings: ids.settings.filter(|_| self.data.settings_conflict.is_none()),
+		};
+		let settings = change_ids.settings.map(|_| Settings {
+			revision: self.data.settings_base_revision.unwrap_or(self.data.settings_revision),
+			..self.data.settings()
+		});
+		SyncRequest {
+			changes,
+			verifier: self.data.verifier.clone(),
+			identities: self.data.identities.clone(),
+			alias_changes,
+			base_revisions,
+			change_ids,
+			settings,
+			// revision 0 may have unversioned domains, which only a full response includes
+			since: Some(self.data.revision).filter(|revision| !full && *revision > 0),
+		}
+	}
+
+	// changes made before base revisions were recorded are based on the last synced revision
+	fn base_revision(&self, domain: &str) -> u64 {
+		self.data.base_revisions.get(domain).copied()
+			.unwrap_or_else(|| self.synced_revision(domain))
+	}
+
+	fn synced_revision(&self, domain: &str) -> u64 {
+		self.data.domains.get(domain).map_or(0, |config| config.revision)
+	}
+
+	pub fn uses_encrypted_sync(&self) -> bool {
+		self.data.sync_key.is_some()
+	}
+
+	/// Sync via an encrypted vault from now on, using a key from `SyncKey::for_sealed`
+	/// (if the account already has a vault) or `SyncKey::new`. The vault's revisions
+	/// are unrelated to the server's, so the first encrypted sync uploads everything.
+	pub fn set_sync_key(&mut self, key: SyncKey) {
+		if self.data.sync_key.is_none() {
+			self.data.revision = 0;
+			for domain_config in self.data.domains.values_mut() {
+				domain_config.revision = 0;
+			}
+			self.data.base_revisions.clear();
+			self.data.settings_revision = 0;
+			self.data.settings_base_revision = None;
+			self.data.conflicts.clear();
+			self.data.settings_conflict = None;
+		}
+		self.data.sync_key = Some(key);
+		self.dirty = true;
+	}
+
+	/// Merge local changes into the account's vault (if it has one yet), as the server
+	/// does for plaintext syncs. The result should be uploaded, and its response applied
+	/// with `update_after_sync` once the upload is accepted.
+	pub fn vault_sync(&self, vault: Option<&Vault>, full: bool, nonce: [u8; NONCE_LENGTH]) -> Result<VaultSync> {
+		let key = self.data.sync_key.as_ref().ok_or_else(|| anyhow!("No sync passphrase has been set"))?;
+		let mut stored: ConfigFile = match vault {
+			Some(vault) => {
+				let plaintext = key.open(&vault.sealed)?;
+				CONFIG_SCHEMA.load(std::str::from_utf8(&plaintext)?).context("Loading vault")?.data
+			},
+			None => Default::default(),
+		};
+		let mut request = self.sync_request(full || self.data.revision == 0);
+		if vault.is_none() && request.settings.is_none() {
+			// a new vault starts with our settings, even if they haven't changed
+			request.settings = Some(self.data.settings());
+		}
+		let response = sync::apply_request(&mut stored, request);
+		let plaintext = Zeroizing::new(serde_json::to_vec(&stored)?);
+		let upload = VaultUpload {
+			base_revision: vault.map_or(0, |vault| vault.revision),
+			sealed: key.seal(&plaintext, nonce),
+		};
+		Ok(VaultSync { upload, response })
+	}
+
+	pub fn has_unsynced_changes(&self) -> bool {
+		!self.data.changes.is_empty() || !self.data.alias_changes.is_empty() || self.data.change_ids.settings.is_some()
+	}
+
+	/// Unsynced domain and alias changes, including deletions
+	pub fn pending_changes(&self) -> impl Iterator<Item=PendingChange<'_>> + '_ {
+		let domains = self.data.changes.iter().map(|(name, change)| {
+			let kind = match change {
+				_ if self.data.conflicts.contains(name) => PendingKind::Conflicted,
+				Change::Delete => PendingKind::Deleted,
+				Change::Set(_) if self.data.domains.contains_key(name) => PendingKind::Updated,
+				Change::Set(_) => PendingKind::Added,
+			};
+			PendingChange { name, kind }
+		});
+		let aliases = self.data.alias_changes.iter().map(|(name, change)| {
+			let kind = match change {
+				Change::Delete => PendingKind::Unaliased,
+				Change::Set(_) => PendingKind::Aliased,
+			};
+			PendingChange { name, kind }
+		});
+		let settings = self.data.change_ids.settings.map(|_| PendingChange {
+			name: "settings",
+			kind: if self.data.settings_conflict.is_some() { PendingKind::Conflicted } else { PendingKind::Updated },
+		});
+		domains.chain(aliases).chain(settings)
+	}
+
 	pub fn full_changes(&self) -> Changes {
 		let mut result: Changes = self.data.domains.iter()
 			.map(|(k,v)| (k.clone(), Change::Set(v.clone())))
@@ -231,66 +962,1250 @@ impl Config {
 	pub fn domain_list(&self) -> impl Iterator<Item=&str> + '_ {
 		let mut set = BTreeSet::from_iter(self.domains.keys());
 		set.extend(self.changes().keys());
-		set.into_iter().map(|s| s.as_ref())
+		set.into_iter().filter(|domain| self.stored(domain).is_some()).map(|s| s.as_ref())
+	}
+
+	pub fn alias_list(&self) -> impl Iterator<Item=(&str, &str)> + '_ {
+		let mut set = BTreeSet::from_iter(self.data.aliases.keys());
+		set.extend(self.data.alias_changes.keys());
+		set.into_iter().filter_map(|alias| self.alias(alias).map(|canonical| (alias.as_str(), canonical)))
 	}
 
-	pub fn domains_matching<'a, 'b>(&'a self, partial: &'b str, limit: usize) -> Vec<&'a str> {
-		let sorted = BTreeSet::from_iter(self.domain_list().map(LengthStr::new).into_iter());
+	/// Domains (and aliases) containing `partial`, with aliases resolved to their canonical domain
+	pub fn domains_matching<'a>(&'a self, partial: &str, limit: usize) -> Vec<&'a str> {
+		let partial: &str = &normalize_domain(partial);
+		let names = self.domain_list().map(|domain| (domain, domain))
+			.chain(self.alias_list());
+		let sorted = BTreeSet::from_iter(names
+			.filter(|(name, resolved)| name.contains(partial) && *resolved != partial)
+			.map(|(_, resolved)| LengthStr::new(resolved)));
 		sorted.into_iter()
-			.filter(|candidate| candidate.value.contains(partial) && !(candidate.value == partial))
 			.map(|length_str| length_str.value)
 			.take(limit)
 			.collect()
 	}
-	
-	pub fn extract_domain<'a, 'b>(&'a self, value: &'b str) -> Option<&'b str> {
-		let extracted = self.extractor.extract(value);
-		if extracted == value {
-			None
-		} else {
-			Some(extracted)
+
+	// The canonical domain for exactly this alias
+	fn alias(&self, alias: &str) -> Option<&str> {
+		match self.data.alias_changes.get(alias) {
+			Some(Change::Delete) => None,
+			Some(Change::Set(canonical)) => Some(canonical),
+			None => self.data.aliases.get(alias).map(|s| s.as_str()),
 		}
 	}
 
-	fn override_for(&self, domain: &str) -> Option<&Change<DomainConfig>> {
+	/// The normalized name of a domain, following aliases to their canonical domain.
+	/// This is the name to look up and generate passwords with.
+	pub fn resolve<'b>(&'b self, domain: &'b str) -> Cow<'b, str> {
+		let mut resolved = normalize_domain(domain);
+		for _ in 0..MAX_ALIAS_DEPTH {
+			match self.alias(&resolved) {
+				Some(canonical) => resolved = Cow::Borrowed(canonical),
+				None => break,
+			}
+		}
+		resolved
+	}
+
+	/// Make `alias` share the config (and password) of `canonical`
+	pub fn add_alias(&mut self, alias: &str, canonical: &str) -> Result<()> {
+		let alias = normalize_domain(alias).into_owned();
+		let canonical = self.resolve(canonical).into_owned();
+		if alias == canonical {
+			bail!("Can't alias {} to itself", alias);
+		}
+		if self.stored(&alias).is_some() {
+			bail!("{} has its own config; remove it before making it an alias", alias);
+		}
+		if self.alias(&alias) == Some(&canonical) {
+			return Ok(());
+		}
+		info!("Aliasing {} to {}", &alias, &canonical);
+		self.set_alias_change(alias, Change::Set(canonical));
+		self.dirty = true;
+		Ok(())
+	}
+
+	pub fn remove_alias(&mut self, alias: &str) -> Result<()> {
+		let alias = normalize_domain(alias).into_owned();
+		if self.alias(&alias).is_none() {
+			bail!("{} is not an alias", alias);
+		}
+		if self.data.aliases.contains_key(&alias) {
+			self.set_alias_change(alias, Change::Delete);
+		} else {
+			self.discard_alias_change(&alias);
+		}
+		self.dirty = true;
+		Ok(())
+	}
+	
+	pub fn app_list(&self) -> impl Iterator<Item=(&str, &str)> + '_ {
+		self.data.apps.iter().map(|(app, domain)| (app.as_str(), domain.as_str()))
+	}
+
+	/// Map a mobile app to `domain`, for when its package name doesn't match
+	pub fn set_app_domain(&mut self, app: &str, domain: &str) -> Result<()> {
+		let app = self.extractor.app(app).unwrap_or(app);
+		if app.is_empty() {
+			bail!("Empty app identifier");
+		}
+		let domain = self.resolve(domain).into_owned();
+		if self.data.apps.get(app) != Some(&domain) {
+			info!("Mapping app {} to {}", app, &domain);
+			self.data.apps.insert(app.to_owned(), domain);
+			self.settings_changed();
+		}
+		Ok(())
+	}
+
+	pub fn remove_app(&mut self, app: &str) -> Result<()> {
+		let app = self.extractor.app(app).unwrap_or(app);
+		if self.data.apps.remove(app).is_none() {
+			bail!("No domain is set for app {}", app);
+		}
+		self.settings_changed();
+		Ok(())
+	}
+
+	pub fn set_defaults(&mut self, defaults: DomainConfig) -> Result<()> {
+		defaults.validate().context("Invalid defaults")?;
+		if self.data.defaults != defaults {
+			info!("Updated defaults");
+			self.data.defaults = defaults;
+			self.settings_changed();
+		}
+		Ok(())
+	}
+
+	// `defaults` and `apps` are synced together, as `Settings`
+	fn settings_changed(&mut self) {
+		if self.data.change_ids.settings.is_none() {
+			self.data.settings_base_revision = Some(self.data.settings_revision);
+		}
+		let id = self.next_change_id();
+		self.data.change_ids.settings = Some(id);
+		self.dirty = true;
+	}
+
+	fn discard_settings_change(&mut self) {
+		self.data.change_ids.settings = None;
+		self.data.settings_base_revision = None;
+	}
+
+	/// The other device's settings, if they conflict with local changes
+	pub fn settings_conflict(&self) -> Option<&Settings> {
+		self.data.settings_conflict.as_ref()
+	}
+
+	pub fn resolve_settings_conflict(&mut self, resolution: Resolution) -> Result<()> {
+		let Some(remote) = self.data.settings_conflict.take() else {
+			bail!("Settings have no conflict");
+		};
+		info!("Resolving settings conflict with {:?} changes", resolution);
+		if resolution == Resolution::Remote {
+			self.data.set_settings(remote);
+			self.discard_settings_change();
+		} else {
+			// overwrite the other device's change
+			self.data.settings_base_revision = Some(remote.revision);
+		}
+		self.dirty = true;
+		Ok(())
+	}
+
+	pub fn extract_domain<'b>(&self, value: &'b str) -> Option<Cow<'b, str>> {
+		// bare package names are only recognised once they have an override
+		if let Some(domain) = self.data.apps.get(self.extractor.app(value).unwrap_or(value)) {
+			return Some(Cow::Owned(domain.clone()));
+		}
+		let mut extracted = self.extractor.extract(value);
+		let legacy = self.extractor.extract_legacy(value);
+		// keep using the domain that an existing config was stored under
+		if legacy != extracted && self.is_configured(&legacy) && !self.is_configured(&extracted) {
+			extracted = legacy;
+		}
+		if extracted == value {
+			None
+		} else {
+			Some(extracted)
+		}
+	}
+
+	fn is_configured(&self, domain: &str) -> bool {
+		self.for_domain(domain).explicit().is_some()
+	}
+
+	fn override_for(&self, domain: &str) -> Option<&Change<DomainConfig>> {
 		self.changes.get(domain)
 	}
 	
-	pub fn update_after_sync(&mut self, merged: Domains) {
-		self.data.domains = merged;
-		self.data.changes = Default::default();
+	pub fn update_after_sync(&mut self, response: SyncResponse) -> Result<()> {
+		match response.since {
+			None => self.data.domains = response.domains,
+			Some(since) if since == self.data.revision => {
+				for domain in &response.deleted {
+					self.data.domains.remove(domain);
+				}
+				self.data.domains.extend(response.domains);
+			},
+			Some(since) => bail!("Sync response is relative to revision {}, but we're at {}. Try a full sync.", since, self.data.revision),
+		}
+		self.data.revision = response.revision;
+
+		// keep conflicting changes until they're resolved
+		self.data.conflicts.extend(response.conflicts);
+
+		// Clear the changes which the server acknowledged, unless they've been modified
+		// since. Older servers don't acknowledge changes, but apply all of them.
+		let acknowledged = response.acknowledged.as_ref();
+		let ids = &self.data.change_ids;
+		let conflicts = &self.data.conflicts;
+		let applied_domains: Vec<String> = self.data.changes.keys()
+			.filter(|domain| !conflicts.contains(*domain))
+			.filter(|domain| acknowledged.is_none_or(|ack| ack.domains.get(*domain) == Some(&ids.domain(domain))))
+			.cloned()
+			.collect();
+		let applied_aliases: Vec<String> = self.data.alias_changes.keys()
+			.filter(|alias| acknowledged.is_none_or(|ack| ack.aliases.get(*alias) == Some(&ids.alias(alias))))
+			.cloned()
+			.collect();
+		for domain in &applied_domains {
+			self.discard_change(domain);
+		}
+		// changes modified since an earlier version was applied are now based on that version
+		let rebased: Vec<String> = self.data.changes.keys()
+			.filter(|domain| acknowledged.is_some_and(|ack| ack.domains.contains_key(*domain)))
+			.cloned()
+			.collect();
+		for domain in rebased {
+			let revision = self.synced_revision(&domain);
+			self.data.base_revisions.insert(domain, revision);
+		}
+		for alias in &applied_aliases {
+			self.discard_alias_change(alias);
+		}
+		let changes = &self.data.changes;
+		self.data.conflicts.retain(|domain| changes.contains_key(domain));
+		self.update_settings_after_sync(response.settings, response.settings_conflict, acknowledged.and_then(|ack| ack.settings));
+
+		self.data.verifier = newest_verifier(self.data.verifier.take(), response.verifier);
+		merge_identities(&mut self.data.identities, response.identities);
+		self.data.aliases = response.aliases;
+		self.dirty = true;
+		self.migrate();
+		Ok(())
+	}
+
+	fn update_settings_after_sync(&mut self, remote: Option<Settings>, conflict: bool, acknowledged: Option<u64>) {
+		let Some(remote) = remote else {
+			if self.data.change_ids.settings.is_some() {
+				warn!("The server doesn't support syncing settings");
+				self.discard_settings_change();
+			}
+			return;
+		};
+		// the local change stays based on the revision it was made on (so that changes
+		// from other devices are detected) until the server applies it
+		match (acknowledged, self.data.change_ids.settings) {
+			(Some(acknowledged), Some(id)) if acknowledged == id => self.discard_settings_change(),
+			// an earlier version of the change was applied, and it's been modified since
+			(Some(_), Some(_)) => self.data.settings_base_revision = Some(remote.revision),
+			_ => (),
+		}
+		if conflict {
+			self.data.settings_conflict = Some(remote.clone());
+		}
+		self.data.settings_revision = remote.revision;
+		if self.data.change_ids.settings.is_none() {
+			if remote.revision == 0 && self.data.settings() != remote {
+				// settings have never been synced, so upload ours instead of losing them
+				self.settings_changed();
+			} else {
+				self.data.set_settings(remote);
+			}
+		}
+	}
+
+	pub fn conflicts(&self) -> impl Iterator<Item=Conflict<'_>> + '_ {
+		self.data.conflicts.iter().map(|domain| Conflict {
+			domain,
+			local: self.stored(domain),
+			remote: self.data.domains.get(domain),
+		})
+	}
+
+	/// Keep one side of a conflict. Keeping the local change
+	/// overwrites the other device's change at the next sync.
+	pub fn resolve_conflict(&mut self, domain: &str, resolution: Resolution) -> Result<()> {
+		let domain = self.resolve(domain).into_owned();
+		if !self.data.conflicts.remove(&domain) {
+			bail!("{} has no conflict", &domain);
+		}
+		info!("Resolving conflict for {} with {:?} changes", &domain, resolution);
+		if resolution == Resolution::Remote {
+			self.discard_change(&domain);
+		} else {
+			// overwrite the other device's change
+			let revision = self.synced_revision(&domain);
+			self.data.base_revisions.insert(domain, revision);
+		}
+		self.dirty = true;
+		Ok(())
+	}
+
+	pub fn identity_names(&self) -> impl Iterator<Item=&str> + '_ {
+		self.data.identities.keys().map(|s| s.as_str())
+	}
+
+	fn identity(&self, name: &str) -> Result<&Identity> {
+		self.data.identities.get(name).ok_or_else(|| anyhow!("Unknown identity: {}", name))
+	}
+
+	/// The defaults for new domains using the given identity
+	pub fn defaults_for(&self, identity: Option<&str>) -> Result<DomainConfig> {
+		match identity {
+			None => Ok(self.data.defaults.clone()),
+			Some(name) => Ok(DomainConfig {
+				identity: Some(name.to_owned()),
+				..self.identity(name)?.defaults.clone()
+			}),
+		}
+	}
+
+	pub fn verifier_for(&self, identity: Option<&str>) -> Option<&Verifier> {
+		match identity {
+			None => self.data.verifier.as_ref(),
+			Some(name) => self.data.identities.get(name).and_then(|i| i.verifier.as_ref()),
+		}
+	}
+
+	/// Set the verifier for an identity, creating the identity if it doesn't exist
+	pub fn set_verifier(&mut self, identity: Option<&str>, verifier: Verifier) {
+		match identity {
+			None => self.data.verifier = Some(verifier),
+			Some(name) => self.data.identities.entry(name.to_owned()).or_default().verifier = Some(verifier),
+		}
 		self.dirty = true;
 	}
 
+	/// Set the defaults for new domains of an identity, creating the identity if it doesn't exist
+	pub fn set_identity_defaults(&mut self, name: &str, defaults: DomainConfig, timestamp: u64) -> Result<()> {
+		defaults.validate().with_context(|| format!("Invalid defaults for {}", name))?;
+		// `defaults_for` fills in the identity
+		let defaults = DomainConfig { identity: None, ..defaults };
+		let identity = self.data.identities.entry(name.to_owned()).or_default();
+		if identity.defaults != defaults {
+			info!("Updated defaults for identity {}", name);
+			identity.defaults = defaults;
+			identity.defaults_updated = timestamp;
+			self.dirty = true;
+		}
+		Ok(())
+	}
+
+	/// Check a master password against the identity's verifier, if there is one
+	pub fn check_password(&self, identity: Option<&str>, password: Password) -> Option<bool> {
+		self.verifier_for(identity).map(|verifier| verifier.verify(password))
+	}
+
 	pub fn update_after_login(&mut self, auth: Authentication) {
 		self.data.authentication = Some(auth);
 		self.dirty = true;
 	}
 
-	pub fn for_domain(&self, domain: &str) -> Defaulted<&DomainConfig> {
-		let stored = self.domains.get(domain);
-		let found = match self.override_for(domain) {
+	/// The config for a domain (or the domain it's an alias of). Unconfigured domains
+	/// inherit from their nearest configured parent, see `inherited`.
+	pub fn for_domain(&self, domain: &str) -> Defaulted<Cow<'_, DomainConfig>> {
+		let domain = self.resolve(domain);
+		if let Some(f) = self.stored(&domain) {
+			return Defaulted::Explicit(Cow::Borrowed(f));
+		}
+		if let Some(duplicate) = self.duplicates().remove(domain.as_ref()).and_then(|domains| domains.into_iter().next()) {
+			return Defaulted::Explicit(Cow::Owned(self.generated_as(&duplicate)));
+		}
+		match self.inherited(&domain) {
+			Some((from, parent)) => Defaulted::Inherited { from, value: Cow::Owned(parent.for_subdomain()) },
+			None => Defaulted::Default(Cow::Borrowed(&self.defaults)),
+		}
+	}
+
+	// The nearest config stored for a parent of `domain`. At each level, a wildcard
+	// (e.g. `*.example.com`) takes precedence over the parent itself.
+	fn inherited(&self, domain: &str) -> Option<(String, &DomainConfig)> {
+		let mut parent = domain;
+		while let Some((_, rest)) = parent.split_once('.') {
+			let wildcard = format!("*.{}", rest);
+			if let Some(domain_config) = self.stored(&wildcard) {
+				return Some((wildcard, domain_config));
+			}
+			if let Some(domain_config) = self.stored(rest) {
+				return Some((rest.to_owned(), domain_config));
+			}
+			parent = rest;
+		}
+		None
+	}
+
+	// The config stored under exactly this name
+	fn stored(&self, domain: &str) -> Option<&DomainConfig> {
+		match self.override_for(domain) {
 			Some(Change::Delete) => None,
 			Some(Change::Set(ch)) => Some(ch),
-			None => stored,
+			None => self.domains.get(domain),
+		}
+	}
+
+	fn remove_exact(&mut self, domain: &str) {
+		if self.data.domains.contains_key(domain) {
+			self.set_change(domain.to_owned(), Change::Delete);
+		} else {
+			self.discard_change(domain);
+		}
+		self.dirty = true;
+	}
+
+	// Local changes get a new id each time they're modified, so that a sync
+	// only clears them if the server acknowledges the version it was sent.
+	fn next_change_id(&mut self) -> u64 {
+		self.data.last_change_id += 1;
+		self.data.last_change_id
+	}
+
+	fn set_change(&mut self, domain: String, change: Change<DomainConfig>) {
+		let id = self.next_change_id();
+		self.data.change_ids.domains.insert(domain.clone(), id);
+		if !self.data.base_revisions.contains_key(&domain) {
+			let revision = self.synced_revision(&domain);
+			self.data.base_revisions.insert(domain.clone(), revision);
+		}
+		self.data.changes.insert(domain, change);
+	}
+
+	fn discard_change(&mut self, domain: &str) {
+		self.data.changes.remove(domain);
+		self.data.change_ids.domains.remove(domain);
+		self.data.base_revisions.remove(domain);
+	}
+
+	fn set_alias_change(&mut self, alias: String, change: Change<String>) {
+		let id = self.next_change_id();
+		self.data.change_ids.aliases.insert(alias.clone(), id);
+		self.data.alias_changes.insert(alias, change);
+	}
+
+	fn discard_alias_change(&mut self, alias: &str) {
+		self.data.alias_changes.remove(alias);
+		self.data.change_ids.aliases.remove(alias);
+	}
+
+	/// Remove a domain's config, so that it reverts to the defaults (or its parent's config).
+	/// Synced domains are deleted on the server at the next sync.
+	pub fn remove(&mut self, domain: &str) -> Result<()> {
+		let domain = self.resolve(domain).into_owned();
+		self.check_duplicates(&domain)?;
+		if self.stored(&domain).is_none() {
+			bail!("{} is not configured", &domain);
+		}
+		info!("Removing domain {}", &domain);
+		self.remove_exact(&domain);
+		Ok(())
+	}
+
+	/// Move a domain's config to a new name, e.g. when a site changes domain.
+	/// Passwords are still generated from the original name, and aliases follow the config.
+	pub fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+		let from = self.resolve(from).into_owned();
+		let to = normalize_domain(to).into_owned();
+		self.check_duplicates(&from)?;
+		let Some(existing) = self.stored(&from) else {
+			bail!("{} is not configured", &from);
 		};
-		match found {
-			Some(f) => Defaulted::Explicit(f),
-			None => Defaulted::Default(&self.defaults)
+		if from == to {
+			bail!("Can't rename {} to itself", &from);
+		}
+		if self.stored(&to).is_some() {
+			bail!("{} is already configured", &to);
 		}
+		if let Some(canonical) = self.alias(&to) {
+			bail!("{} is an alias of {}", &to, canonical);
+		}
+		let mut domain_config = existing.clone();
+		domain_config.generate_as.get_or_insert_with(|| from.clone());
+		let length = domain_config.length;
+		self.add_keeping_length(to.clone(), domain_config, length)?;
+		self.remove_exact(&from);
+
+		let aliases: Vec<String> = self.alias_list()
+			.filter(|(_, canonical)| *canonical == from)
+			.map(|(alias, _)| alias.to_owned())
+			.collect();
+		for alias in aliases {
+			self.set_alias_change(alias, Change::Set(to.clone()));
+		}
+		for domain in self.data.apps.values_mut() {
+			if *domain == from {
+				domain.clone_from(&to);
+			}
+		}
+		info!("Renamed {} to {}", &from, &to);
+		Ok(())
+	}
+
+	/// Increment the rotation of a domain (which changes its password),
+	/// returning the new rotation.
+	pub fn rotate(&mut self, domain: &str, timestamp: u64) -> Result<u32> {
+		let mut domain_config = self.for_domain(domain).underlying().into_owned();
+		domain_config.rotation += 1;
+		domain_config.rotations.push(Rotation { rotation: domain_config.rotation, timestamp, extra: Default::default() });
+		let rotation = domain_config.rotation;
+		self.add(domain.to_owned(), domain_config)?;
+		Ok(rotation)
+	}
+
+	/// Like `for_domain`, but unconfigured domains use the defaults of the given identity
+	pub fn for_domain_as(&self, domain: &str, identity: Option<&str>) -> Result<Defaulted<DomainConfig>> {
+		Ok(match self.for_domain(domain) {
+			Defaulted::Default(_) => Defaulted::Default(self.defaults_for(identity)?),
+			found => found.map(Cow::into_owned),
+		})
+	}
+
+	pub fn migration(&self) -> Option<&Migration> {
+		self.data.migration.as_ref()
+	}
+
+	/// Start changing the master password of an identity to the one described by `verifier`
+	pub fn start_migration(&mut self, identity: Option<&str>, verifier: Verifier) -> Result<()> {
+		if self.data.migration.is_some() {
+			bail!("A master password migration is already in progress");
+		}
+		self.data.migration = Some(Migration { identity: identity.map(|s| s.to_owned()), verifier, extra: Default::default() });
+		self.dirty = true;
+		Ok(())
+	}
+
+	pub fn abandon_migration(&mut self) {
+		if self.data.migration.take().is_some() {
+			self.dirty = true;
+		}
+	}
+
+	fn current_migration(&self) -> Result<&Migration> {
+		self.migration().ok_or_else(|| anyhow!("No master password migration in progress"))
+	}
+
+	/// Configured domains of the migrating identity which haven't been migrated yet
+	pub fn unmigrated_domains(&self) -> Result<Vec<String>> {
+		let migration = self.current_migration()?;
+		Ok(self.domain_list()
+			.filter(|domain| self.for_domain(domain).explicit().is_some_and(|config| {
+				config.identity == migration.identity && config.migrated != Some(migration.id())
+			}))
+			.map(|domain| domain.to_owned())
+			.collect())
+	}
+
+	pub fn migrate_password(&self, domain: &str, old: Password, new: Password) -> Result<MigratedPassword> {
+		self.current_migration()?;
+		let domain_config = self.for_domain(domain).underlying();
+		let resolved = self.resolve(domain);
+		let generate = |password| password::generate(Domain(&resolved), password, &domain_config)
+			.with_context(|| format!("Generating password for {}", domain));
+		Ok(MigratedPassword { old: generate(old)?, new: generate(new)? })
+	}
+
+	pub fn mark_migrated(&mut self, domain: &str) -> Result<()> {
+		let id = self.current_migration()?.id();
+		let domain_config = self.for_domain(domain).explicit()
+			.ok_or_else(|| anyhow!("Domain {} is not configured", domain))?;
+		self.add(domain.to_owned(), DomainConfig { migrated: Some(id), ..domain_config.into_owned() })
 	}
 
-	pub fn add(&mut self, domain: String, domain_config: DomainConfig) {
+	/// Complete the migration, replacing the identity's verifier
+	pub fn finish_migration(&mut self) -> Result<()> {
+		let remaining = self.unmigrated_domains()?;
+		if !remaining.is_empty() {
+			bail!("{} domains have not been migrated", remaining.len());
+		}
+		let migration = self.data.migration.take().expect("current_migration");
+		self.set_verifier(migration.identity.as_deref(), migration.verifier);
+		Ok(())
+	}
+
+	/// Store the config for a domain (or the domain it's an alias of)
+	pub fn add(&mut self, domain: String, domain_config: DomainConfig) -> Result<()> {
+		let length = self.for_domain(&domain).as_ref().length;
+		self.add_keeping_length(domain, domain_config, length)
+	}
+
+	// `add`, accepting `kept_length` (the length in effect before this change) even if
+	// it's no longer valid, so that such configs can still be rotated, renamed or migrated
+	fn add_keeping_length(&mut self, domain: String, domain_config: DomainConfig, kept_length: usize) -> Result<()> {
+		let domain = self.resolve(&domain).into_owned();
+		if domain.contains('*') && domain.strip_prefix("*.").is_none_or(|parent| parent.contains('*')) {
+			bail!("Invalid domain {}: wildcards must be a leading `*.`", &domain);
+		}
+		domain_config.validate_keeping_length(Some(kept_length)).with_context(|| format!("Invalid config for {}", &domain))?;
+		if let Some(identity) = &domain_config.identity {
+			if !self.data.identities.contains_key(identity) {
+				info!("Adding identity {}", identity);
+				self.data.identities.insert(identity.clone(), Default::default());
+			}
+		}
 		if let Some(existing) = self.for_domain(&domain).explicit() {
-			if existing == &domain_config {
+			if *existing == domain_config {
 				info!("Skipping save for unchanged domain {}", &domain);
-				return;
+				return Ok(());
 			}
 		}
 
 		info!("Updated domain {}", &domain);
-		self.data.changes.insert(domain, Change::Set(domain_config));
-		info!("Changes is now: {:?}", &self.data.changes);
+		self.set_change(domain, Change::Set(domain_config));
+		info!("Changes is now: {:?}", self.data.changes.keys().collect::<Vec<_>>());
 		self.dirty = true;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+pub mod test {
+	use super::*;
+
+	#[test]
+	fn test_length_validation() {
+		let mut config = Config::default();
+		assert!(config.add("example.com".to_owned(), DomainConfig::default().with_length(MIN_LENGTH - 1)).is_err());
+		assert!(config.add("example.com".to_owned(), DomainConfig::default().with_length(MAX_LENGTH + 1)).is_err());
+		assert!(config.add("example.com".to_owned(), DomainConfig::default().with_length(MAX_LENGTH)).is_ok());
+
+		// lengths stored before they were validated can be kept, but not newly set
+		let mut config = Config::deserialize(&format!(r#"{{
+			"version": {},
+			"domains": {{ "short.com": {{ "length": 3 }}, "*.short.org": {{ "length": 3 }} }}
+		}}"#, CONFIG_VERSION)).unwrap();
+		assert!(config.add("short.com".to_owned(), DomainConfig::default().with_length(2)).is_err());
+		assert!(config.add("example.com".to_owned(), DomainConfig::default().with_length(3)).is_err());
+		config.rotate("short.com", 100).unwrap();
+		config.rotate("a.short.org", 100).unwrap();
+		config.rename("short.com", "short.net").unwrap();
+		config.start_migration(None, Verifier::new(Password("new"), [0; 16], 100)).unwrap();
+		for domain in config.unmigrated_domains().unwrap() {
+			config.mark_migrated(&domain).unwrap();
+		}
+		config.finish_migration().unwrap();
+		assert_eq!(config.for_domain("short.net").as_ref().length, 3);
+	}
+
+	#[test]
+	fn test_post_suffix_validation() {
+		let mut config = Config::default();
+		let with_post_suffix = |post_suffix: &str| DomainConfig { post_suffix: Some(post_suffix.to_owned()), ..Default::default() };
+		assert!(config.add("example.com".to_owned(), with_post_suffix("0123456789")).is_err());
+		assert!(config.add("example.com".to_owned(), DomainConfig {
+			policy: Policy { forbidden: Some("!".to_owned()), ..Default::default() },
+			..with_post_suffix("!")
+		}).is_err());
+		assert!(config.add("example.com".to_owned(), with_post_suffix("!")).is_ok());
+
+		let changes = serde_json::to_string(config.changes()).unwrap();
+		assert_eq!(changes, r#"{"example.com":{"Set":{"length":10,"post_suffix":"!"}}}"#);
+		let parsed: Changes = serde_json::from_str(&changes).unwrap();
+		assert!(matches!(&parsed["example.com"], Change::Set(c) if c.post_suffix.as_deref() == Some("!")));
+	}
+
+	#[test]
+	fn test_debug_redacts_suffixes() {
+		let domain_config = DomainConfig { suffix: Some("secret1".to_owned()), post_suffix: Some("secret2".to_owned()), ..Default::default() };
+		let debug = format!("{:?}", domain_config);
+		assert!(!debug.contains("secret"), "{}", debug);
+		assert!(debug.contains("<redacted>"), "{}", debug);
+	}
+
+	#[test]
+	fn test_identities() {
+		let mut config = Config::default();
+		let work = DomainConfig { identity: Some("work".to_owned()), ..Default::default() };
+		assert!(config.defaults_for(Some("work")).is_err());
+		config.add("example.com".to_owned(), work.clone()).unwrap();
+		assert_eq!(config.identity_names().collect::<Vec<_>>(), vec!["work"]);
+		assert_eq!(config.defaults_for(Some("work")).unwrap(), work);
+
+		config.set_verifier(Some("work"), Verifier::new(Password("hunter2"), [0; 16], 0));
+		assert_eq!(config.check_password(Some("work"), Password("hunter2")), Some(true));
+		assert_eq!(config.check_password(None, Password("hunter2")), None);
+
+		assert!(config.set_identity_defaults("work", DomainConfig::default().with_length(2), 10).is_err());
+		config.set_identity_defaults("work", DomainConfig::default().with_length(16), 10).unwrap();
+		assert_eq!(config.defaults_for(Some("work")).unwrap(), DomainConfig { length: 16, ..work.clone() });
+
+		// the newest defaults win when syncing
+		let synced = |length: usize, defaults_updated: u64| Identities::from([("work".to_owned(), Identity {
+			defaults: DomainConfig::default().with_length(length),
+			defaults_updated,
+			..Default::default()
+		})]);
+		config.update_after_sync(SyncResponse { identities: synced(20, 5), ..Default::default() }).unwrap();
+		assert_eq!(config.defaults_for(Some("work")).unwrap().length, 16);
+		config.update_after_sync(SyncResponse { identities: synced(20, 15), ..Default::default() }).unwrap();
+		assert_eq!(config.defaults_for(Some("work")).unwrap().length, 20);
+		assert!(config.verifier_for(Some("work")).is_some());
+	}
+
+	#[test]
+	fn test_migration() {
+		let mut config = Config::default();
+		config.add("example.com".to_owned(), DomainConfig::default()).unwrap();
+		config.add("example.org".to_owned(), DomainConfig::default()).unwrap();
+		config.add("work.com".to_owned(), DomainConfig { identity: Some("work".to_owned()), ..Default::default() }).unwrap();
+
+		assert!(config.unmigrated_domains().is_err());
+		config.start_migration(None, Verifier::new(Password("new"), [0; 16], 100)).unwrap();
+		assert!(config.start_migration(None, Verifier::new(Password("new"), [0; 16], 100)).is_err());
+		assert_eq!(config.unmigrated_domains().unwrap(), vec!["example.com", "example.org"]);
+
+		let passwords = config.migrate_password("example.com", Password("old"), Password("new")).unwrap();
+		assert_ne!(passwords.old, passwords.new);
+		config.mark_migrated("example.com").unwrap();
+		assert_eq!(config.for_domain("example.com").as_ref().migrated, Some(100));
+		assert!(matches!(&config.changes()["example.com"], Change::Set(c) if c.migrated == Some(100)));
+		assert!(config.finish_migration().is_err());
+
+		// marking doesn't affect the generated password
+		assert_eq!(config.migrate_password("example.com", Password("old"), Password("new")).unwrap(), passwords);
+
+		config.mark_migrated("example.org").unwrap();
+		config.finish_migration().unwrap();
+		assert!(config.migration().is_none());
+		assert_eq!(config.check_password(None, Password("new")), Some(true));
+	}
+
+	#[test]
+	fn test_extract_domain_prefers_stored_legacy_domain() {
+		let mut config = Config::default();
+		assert_eq!(config.extract_domain("https://me.github.io/").as_deref(), Some("me.github.io"));
+		config.add("github.io".to_owned(), DomainConfig::default()).unwrap();
+		assert_eq!(config.extract_domain("https://me.github.io/").as_deref(), Some("github.io"));
+		config.add("me.github.io".to_owned(), DomainConfig::default()).unwrap();
+		assert_eq!(config.extract_domain("https://me.github.io/").as_deref(), Some("me.github.io"));
+	}
+
+	#[test]
+	fn test_normalize_domains() {
+		let config = Config::deserialize(r#"{
+			"domains": {
+				"Example.com": { "length": 12, "generate_as": "example.com" },
+				"example.com": { "length": 12 },
+				"xn--bcher-kva.de": { "length": 16 }
+			},
+			"changes": {
+				"EXAMPLE.ORG": { "Set": { "length": 18 } }
+			}
+		}"#).unwrap();
+		assert!(config.dirty);
+		// identical, so nothing is lost
+		assert_eq!(*config.for_domain("EXAMPLE.COM").explicit().unwrap(), DomainConfig::default().with_length(12));
+		assert!(matches!(config.changes()["Example.com"], Change::Delete));
+
+		let example = config.for_domain("example.org").explicit().unwrap();
+		assert_eq!(example.length, 18);
+		assert_eq!(example.generate_as.as_deref(), Some("EXAMPLE.ORG"));
+		assert!(!config.changes().contains_key("EXAMPLE.ORG"));
+
+		let bucher = config.for_domain("bücher.de").explicit().unwrap();
+		assert_eq!(bucher.generate_as.as_deref(), Some("xn--bcher-kva.de"));
+		assert!(config.duplicates().is_empty());
+
+		// configs which are no longer valid are still migrated
+		let config = Config::deserialize(r#"{ "domains": { "Example.com": { "length": 3 } } }"#).unwrap();
+		assert_eq!(config.for_domain("example.com").explicit().unwrap().length, 3);
+		let config = Config::deserialize(r#"{ "domains": { "Example.com": { "length": 200 } } }"#).unwrap();
+		assert_eq!(config.for_domain("example.com").explicit().unwrap().length, 24);
+	}
+
+	#[test]
+	fn test_duplicate_domains() {
+		let mut config = Config::deserialize(r#"{
+			"domains": {
+				"xn--bcher-kva.de": { "length": 16, "suffix": "1" }
+			},
+			"changes": {
+				"BÜCHER.de": { "Set": { "length": 16, "suffix": "2" } }
+			}
+		}"#).unwrap();
+		// they'd generate different passwords, so both are kept
+		let duplicates = BTreeMap::from([("bücher.de".to_owned(), vec!["BÜCHER.de".to_owned(), "xn--bcher-kva.de".to_owned()])]);
+		assert_eq!(config.duplicates(), duplicates);
+		assert!(!config.changes().values().any(|change| matches!(change, Change::Delete)));
+		let bucher = config.for_domain("bücher.de").explicit().unwrap();
+		assert_eq!((bucher.suffix.as_deref(), bucher.generate_as.as_deref()), (Some("2"), Some("BÜCHER.de")));
+		assert!(config.remove("bücher.de").is_err());
+
+		assert!(config.keep_duplicate("bücher.de").is_err());
+		config.keep_duplicate("xn--bcher-kva.de").unwrap();
+		assert!(config.duplicates().is_empty());
+		let bucher = config.for_domain("bücher.de").explicit().unwrap();
+		assert_eq!((bucher.suffix.as_deref(), bucher.generate_as.as_deref()), (Some("1"), Some("xn--bcher-kva.de")));
+		assert!(matches!(config.changes()["xn--bcher-kva.de"], Change::Delete));
+		assert!(!config.changes().contains_key("BÜCHER.de"));
+	}
+
+	#[test]
+	fn test_unknown_fields() {
+		let mut config = Config::deserialize(r#"{
+			"domains": {
+				"example.com": {
+					"length": 12,
+					"totp": { "digits": 6 },
+					"policy": { "forbidden": "!", "max_repeat": 2 },
+					"rotation": 1,
+					"rotations": [{ "rotation": 1, "timestamp": 100, "reason": "leaked" }]
+				}
+			},
+			"identities": { "work": { "defaults": { "length": 12 }, "color": "red" } },
+			"future_setting": [1, 2]
+		}"#).unwrap();
+		assert_eq!(config.version, CONFIG_VERSION);
+		assert!(config.dirty);
+
+		let domain_config = config.for_domain("example.com").underlying().into_owned();
+		config.add("example.com".to_owned(), domain_config.with_length(14)).unwrap();
+		let serialized: serde_json::Value = serde_json::from_str(&config.serialize().unwrap()).unwrap();
+		assert_eq!(serialized["future_setting"], serde_json::json!([1, 2]));
+		assert_eq!(serialized["domains"]["example.com"]["totp"], serde_json::json!({ "digits": 6 }));
+		assert_eq!(serialized["changes"]["example.com"]["Set"]["totp"], serde_json::json!({ "digits": 6 }));
+		assert_eq!(serialized["domains"]["example.com"]["policy"]["max_repeat"], serde_json::json!(2));
+		assert_eq!(serialized["domains"]["example.com"]["rotations"][0]["reason"], serde_json::json!("leaked"));
+		assert_eq!(serialized["identities"]["work"]["color"], serde_json::json!("red"));
+		assert_eq!(serialized["version"], serde_json::json!(CONFIG_VERSION));
+	}
+
+	#[test]
+	fn test_rename_hints() {
+		let config = Config::deserialize(r#"{
+			"defaults": { "length": 12, "hint": "default" },
+			"domains": { "example.com": { "length": 12, "hint": "a" } },
+			"changes": {
+				"example.org": { "Set": { "length": 12, "hint": "b" } },
+				"example.net": "Delete"
+			},
+			"identities": { "work": { "defaults": { "length": 12, "hint": "c" } } }
+		}"#).unwrap();
+		assert_eq!(config.defaults.note.as_deref(), Some("default"));
+		assert_eq!(config.for_domain("example.com").as_ref().note.as_deref(), Some("a"));
+		assert_eq!(config.for_domain("example.org").as_ref().note.as_deref(), Some("b"));
+		assert_eq!(config.identities["work"].defaults.note.as_deref(), Some("c"));
+		assert!(config.defaults.extra.is_empty());
+
+		assert!(Config::deserialize(&format!(r#"{{ "version": {} }}"#, CONFIG_VERSION + 1)).is_err());
+	}
+
+	#[test]
+	fn test_aliases() {
+		let mut config = Config::default();
+		config.add("google.com".to_owned(), DomainConfig::default().with_length(16)).unwrap();
+		config.add_alias("YouTube.com", "google.com").unwrap();
+		config.add_alias("youtu.be", "youtube.com").unwrap();
+		assert!(config.add_alias("google.com", "youtu.be").is_err());
+		assert!(config.add_alias("google.com", "example.com").is_err());
+
+		assert_eq!(config.resolve("youtu.be"), "google.com");
+		assert_eq!(config.for_domain("youtube.com").as_ref().length, 16);
+		assert_eq!(config.domains_matching("yout", 5), vec!["google.com"]);
+		assert_eq!(config.alias_list().collect::<Vec<_>>(), vec![("youtu.be", "google.com"), ("youtube.com", "google.com")]);
+
+		config.add("youtube.com".to_owned(), DomainConfig::default().with_length(20)).unwrap();
+		assert_eq!(config.for_domain("google.com").as_ref().length, 20);
+
+		let request = config.sync_request(false);
+		assert_eq!(request.alias_changes.len(), 2);
+		config.remove_alias("youtube.com").unwrap();
+		assert_eq!(config.for_domain("youtube.com").as_ref().length, 10);
+		assert!(config.remove_alias("youtube.com").is_err());
+	}
+
+	#[test]
+	fn test_remove() {
+		let mut config = Config::default();
+		config.data.domains.insert("synced.com".to_owned(), DomainConfig::default().with_length(16));
+		config.add("local.com".to_owned(), DomainConfig::default().with_length(16)).unwrap();
+		config.add("synced.com".to_owned(), DomainConfig::default().with_length(20)).unwrap();
+		assert_eq!(config.pending_changes().map(|change| change.to_string()).collect::<Vec<_>>(),
+			vec!["added: local.com", "updated: synced.com"]);
+
+		config.remove("local.com").unwrap();
+		config.remove("Synced.com").unwrap();
+		assert!(config.remove("synced.com").is_err());
+		assert!(config.for_domain("synced.com").explicit().is_none());
+		assert_eq!(config.domain_list().count(), 0);
+		assert_eq!(config.pending_changes().collect::<Vec<_>>(),
+			vec![PendingChange { name: "synced.com", kind: PendingKind::Deleted }]);
+		assert!(matches!(config.sync_request(false).changes.get("synced.com"), Some(Change::Delete)));
+	}
+
+	#[test]
+	fn test_rename() {
+		let mut config = Config::default();
+		config.data.domains.insert("twitter.com".to_owned(), DomainConfig::default().with_length(16));
+		config.add_alias("twitter.co", "twitter.com").unwrap();
+		let password = |config: &Config, domain: &str| {
+			password::generate(Domain(domain), Password("secret"), &config.for_domain(domain).underlying()).unwrap()
+		};
+		let original = password(&config, "twitter.com");
+
+		config.rename("Twitter.com", "X.com").unwrap();
+		assert_eq!(config.for_domain("x.com").explicit().unwrap().generate_as.as_deref(), Some("twitter.com"));
+		assert_eq!(password(&config, "x.com"), original);
+		assert_eq!(config.resolve("twitter.co"), "x.com");
+		assert!(config.for_domain("twitter.com").explicit().is_none());
+
+		let changes = config.sync_request(false).changes;
+		assert!(matches!(changes.get("twitter.com"), Some(Change::Delete)));
+		assert!(matches!(changes.get("x.com"), Some(Change::Set(_))));
+
+		// renaming again keeps the original generation input
+		config.rename("x.com", "example.com").unwrap();
+		assert_eq!(password(&config, "example.com"), original);
+		assert!(!config.data.changes.contains_key("x.com"));
+		assert!(config.rename("example.com", "twitter.co").is_err());
+		assert!(config.rename("twitter.com", "example.org").is_err());
+	}
+
+	#[test]
+	fn test_conflicts() {
+		let mut config = Config::default();
+		config.data.domains.insert("a.com".to_owned(), DomainConfig { revision: 3, ..DomainConfig::default() });
+		config.add("a.com".to_owned(), DomainConfig::default().with_length(16)).unwrap();
+		config.add("b.com".to_owned(), DomainConfig::default().with_length(16)).unwrap();
+		let request = config.sync_request(false);
+		assert_eq!(request.base_revisions, Revisions::from([("a.com".to_owned(), 3), ("b.com".to_owned(), 0)]));
+
+		let remote = DomainConfig { revision: 5, ..DomainConfig::default().with_length(20) };
+		config.update_after_sync(SyncResponse {
+			domains: Domains::from([("a.com".to_owned(), remote.clone())]),
+			conflicts: BTreeSet::from(["a.com".to_owned()]),
+			..Default::default()
+		}).unwrap();
+		assert_eq!(config.conflicts().collect::<Vec<_>>(), vec![Conflict {
+			domain: "a.com",
+			local: Some(&DomainConfig::default().with_length(16)),
+			remote: Some(&remote),
+		}]);
+		assert_eq!(config.pending_changes().map(|change| change.to_string()).collect::<Vec<_>>(), vec!["conflicted: a.com"]);
+		assert!(config.sync_request(false).changes.is_empty());
+
+		config.resolve_conflict("a.com", Resolution::Local).unwrap();
+		assert_eq!(config.sync_request(false).base_revisions, Revisions::from([("a.com".to_owned(), 5)]));
+		assert!(config.resolve_conflict("a.com", Resolution::Local).is_err());
+	}
+
+	#[test]
+	fn test_base_revision_is_recorded_with_change() {
+		let mut config = Config::default();
+		config.data.domains.insert("a.com".to_owned(), DomainConfig { revision: 3, ..DomainConfig::default() });
+		config.add("a.com".to_owned(), DomainConfig::default().with_length(16)).unwrap();
+		config.add("a.com".to_owned(), DomainConfig::default().with_length(18)).unwrap();
+
+		// another device's change arrives before ours is sent (e.g. by a sync which started first)
+		config.update_after_sync(SyncResponse {
+			domains: Domains::from([("a.com".to_owned(), DomainConfig { revision: 4, ..DomainConfig::default() })]),
+			acknowledged: Some(ChangeIds::default()),
+			..Default::default()
+		}).unwrap();
+		assert_eq!(config.sync_request(false).base_revisions, Revisions::from([("a.com".to_owned(), 3)]));
+
+		let mut stored = ConfigFile {
+			domains: Domains::from([("a.com".to_owned(), DomainConfig { revision: 4, ..DomainConfig::default() })]),
+			revision: 4,
+			..Default::default()
+		};
+		let response = sync::apply_request(&mut stored, config.sync_request(false));
+		assert_eq!(response.conflicts, BTreeSet::from(["a.com".to_owned()]));
+		assert_eq!(stored.domains["a.com"].length, DomainConfig::default().length);
+
+		config.update_after_sync(response).unwrap();
+		config.resolve_conflict("a.com", Resolution::Remote).unwrap();
+		assert!(config.base_revisions.is_empty());
+	}
+
+	#[test]
+	fn test_incremental_sync() {
+		let mut config = Config::default();
+		assert_eq!(config.sync_request(false).since, None);
+		config.update_after_sync(SyncResponse {
+			domains: Domains::from([
+				("a.com".to_owned(), DomainConfig::default()),
+				("b.com".to_owned(), DomainConfig::default()),
+			]),
+			revision: 4,
+			..Default::default()
+		}).unwrap();
+		assert_eq!(config.sync_request(false).since, Some(4));
+		assert_eq!(config.sync_request(true).since, None);
+
+		let c = DomainConfig { revision: 5, ..DomainConfig::default() };
+		config.update_after_sync(SyncResponse {
+			domains: Domains::from([("c.com".to_owned(), c)]),
+			deleted: BTreeSet::from(["a.com".to_owned()]),
+			revision: 6,
+			since: Some(4),
+			..Default::default()
+		}).unwrap();
+		assert_eq!(config.domain_list().collect::<Vec<_>>(), vec!["b.com", "c.com"]);
+		assert_eq!(config.revision, 6);
+		assert!(config.update_after_sync(SyncResponse { since: Some(4), ..Default::default() }).is_err());
+	}
+
+	#[test]
+	fn test_changes_made_during_sync() {
+		let mut config = Config::default();
+		config.add("a.com".to_owned(), DomainConfig::default().with_length(12)).unwrap();
+		config.add("b.com".to_owned(), DomainConfig::default().with_length(12)).unwrap();
+		config.add_alias("c.com", "a.com").unwrap();
+		let request = config.sync_request(false);
+		assert_eq!(request.change_ids.domains.len(), 2);
+
+		// modified while the request is in flight
+		config.add("b.com".to_owned(), DomainConfig::default().with_length(14)).unwrap();
+		config.add("d.com".to_owned(), DomainConfig::default().with_length(14)).unwrap();
+
+		let applied = |domain: &str| (domain.to_owned(), DomainConfig { revision: 2, ..DomainConfig::default().with_length(12) });
+		config.update_after_sync(SyncResponse {
+			domains: Domains::from([applied("a.com"), applied("b.com")]),
+			acknowledged: Some(request.change_ids),
+			..Default::default()
+		}).unwrap();
+		assert_eq!(config.changes().keys().collect::<Vec<_>>(), vec!["b.com", "d.com"]);
+		assert_eq!(config.for_domain("b.com").as_ref().length, 14);
+		// the rest of b.com's change is based on the version which was applied
+		assert_eq!(config.sync_request(false).base_revisions, Revisions::from([("b.com".to_owned(), 2), ("d.com".to_owned(), 0)]));
+		assert!(config.alias_changes.is_empty());
+
+		// servers which don't acknowledge changes applied all of them
+		config.update_after_sync(SyncResponse::default()).unwrap();
+		assert!(!config.has_unsynced_changes());
+		assert!(config.change_ids.is_empty());
+	}
+
+	#[test]
+	fn test_vault_sync() {
+		use crate::encryption::{Sealed, SyncKey};
+
+		// plaintext revisions are unrelated to the vault's
+		let mut a = Config::default();
+		a.add("secret.com".to_owned(), DomainConfig { suffix: Some("!".to_owned()), ..Default::default() }).unwrap();
+		a.update_after_sync(sync::apply_request(&mut ConfigFile::default(), a.sync_request(false))).unwrap();
+		assert_eq!(a.revision, 1);
+		a.set_sync_key(SyncKey::new("passphrase", [1; 16]).unwrap());
+		assert_eq!((a.revision, a.domains["secret.com"].revision), (0, 0));
+
+		let upload = |sync: &VaultSync, revision: u64| Vault { revision, sealed: sync.upload.sealed.clone() };
+		let sync = a.vault_sync(None, false, [2; NONCE_LENGTH]).unwrap();
+		assert_eq!(sync.upload.base_revision, 0);
+		assert!(!serde_json::to_string(&sync.upload).unwrap().contains("secret"));
+		let vault = upload(&sync, 1);
+		a.update_after_sync(sync.response).unwrap();
+		assert!(!a.has_unsynced_changes());
+
+		let mut b = Config::default();
+		let wrong = Sealed { salt: vec![3; 16], ..vault.sealed.clone() };
+		assert!(SyncKey::for_sealed("passphrase", &wrong).is_err());
+		assert!(SyncKey::for_sealed("passphrse", &vault.sealed).is_err());
+		b.set_sync_key(SyncKey::for_sealed("passphrase", &vault.sealed).unwrap());
+		let sync = b.vault_sync(Some(&vault), false, [4; NONCE_LENGTH]).unwrap();
+		b.update_after_sync(sync.response).unwrap();
+		assert_eq!(b.domains["secret.com"].suffix.as_deref(), Some("!"));
+
+		// both devices change the same domain
+		a.add("secret.com".to_owned(), DomainConfig::default().with_length(12)).unwrap();
+		b.add("secret.com".to_owned(), DomainConfig::default().with_length(14)).unwrap();
+		let sync = a.vault_sync(Some(&vault), false, [5; NONCE_LENGTH]).unwrap();
+		assert_eq!(sync.upload.base_revision, 1);
+		let vault = upload(&sync, 2);
+		a.update_after_sync(sync.response).unwrap();
+		let sync = b.vault_sync(Some(&vault), false, [6; NONCE_LENGTH]).unwrap();
+		b.update_after_sync(sync.response).unwrap();
+		assert_eq!(b.conflicts().map(|conflict| conflict.domain).collect::<Vec<_>>(), vec!["secret.com"]);
+		assert_eq!(b.domains["secret.com"].length, 12);
+	}
+
+	#[test]
+	fn test_first_vault_includes_server_data() {
+		use crate::encryption::SyncKey;
+
+		// another device synced before encryption was enabled
+		let mut server = ConfigFile::default();
+		let mut other = Config::default();
+		other.add("remote.com".to_owned(), DomainConfig::default().with_length(12)).unwrap();
+		other.add_alias("alias.com", "remote.com").unwrap();
+		other.set_defaults(DomainConfig::default().with_length(16)).unwrap();
+		other.update_after_sync(sync::apply_request(&mut server, other.sync_request(false))).unwrap();
+
+		// so this one syncs in plaintext before uploading the first vault
+		let mut config = Config::default();
+		config.add("local.com".to_owned(), DomainConfig::default()).unwrap();
+		config.update_after_sync(sync::apply_request(&mut server, config.sync_request(false))).unwrap();
+		let key = SyncKey::new("passphrase", [1; 16]).unwrap();
+		config.set_sync_key(key.clone());
+		let sync = config.vault_sync(None, false, [2; NONCE_LENGTH]).unwrap();
+
+		let vault: ConfigFile = serde_json::from_slice(&key.open(&sync.upload.sealed).unwrap()).unwrap();
+		assert_eq!(vault.domains.keys().collect::<Vec<_>>(), vec!["local.com", "remote.com"]);
+		assert_eq!(vault.domains["remote.com"].length, 12);
+		assert_eq!(vault.aliases.get("alias.com").map(String::as_str), Some("remote.com"));
+		assert_eq!(vault.defaults.length, 16);
+		config.update_after_sync(sync.response).unwrap();
+		assert!(!config.has_unsynced_changes());
+		assert_eq!(config.defaults.length, 16);
+	}
+
+	#[test]
+	fn test_sync_settings() {
+		let mut config = Config::default();
+		config.set_defaults(DomainConfig::default().with_length(16)).unwrap();
+		assert!(config.set_defaults(DomainConfig::default().with_length(2)).is_err());
+		let request = config.sync_request(false);
+		assert_eq!(request.settings.as_ref().map(|settings| settings.defaults.length), Some(16));
+
+		let remote = |length: usize, revision: u64| Settings { defaults: DomainConfig::default().with_length(length), revision, ..Default::default() };
+		config.update_after_sync(SyncResponse {
+			settings: Some(remote(16, 1)),
+			acknowledged: Some(request.change_ids),
+			..Default::default()
+		}).unwrap();
+		assert!(!config.has_unsynced_changes());
+		assert_eq!(config.settings_revision, 1);
+
+		// changed on another device
+		config.set_app_domain("com.example.android", "example.org").unwrap();
+		let request = config.sync_request(false);
+		config.update_after_sync(SyncResponse {
+			settings: Some(remote(20, 2)),
+			settings_conflict: true,
+			acknowledged: Some(ChangeIds { settings: None, ..request.change_ids }),
+			..Default::default()
+		}).unwrap();
+		assert_eq!(config.settings_conflict().map(|settings| settings.defaults.length), Some(20));
+		assert_eq!(config.defaults.length, 16);
+		assert_eq!(config.pending_changes().map(|change| change.to_string()).collect::<Vec<_>>(), vec!["conflicted: settings"]);
+		assert!(config.sync_request(false).settings.is_none());
+
+		config.resolve_settings_conflict(Resolution::Remote).unwrap();
+		assert_eq!(config.defaults.length, 20);
+		assert!(config.apps.is_empty());
+		assert!(!config.has_unsynced_changes());
+
+		// changed locally while another device's change arrives, e.g. from a sync which started first
+		config.set_defaults(DomainConfig::default().with_length(18)).unwrap();
+		config.update_after_sync(SyncResponse {
+			settings: Some(remote(22, 3)),
+			acknowledged: Some(ChangeIds::default()),
+			..Default::default()
+		}).unwrap();
+		let request = config.sync_request(false);
+		assert_eq!(request.settings.as_ref().map(|settings| (settings.defaults.length, settings.revision)), Some((18, 2)));
+		let mut stored = ConfigFile::default();
+		stored.set_settings(remote(22, 3));
+		assert!(sync::apply_request(&mut stored, request).settings_conflict);
+
+		// once resolved, the local change replaces the other device's
+		config.update_after_sync(SyncResponse { settings: Some(remote(22, 3)), settings_conflict: true, ..Default::default() }).unwrap();
+		config.resolve_settings_conflict(Resolution::Local).unwrap();
+		let request = config.sync_request(false);
+		assert_eq!(request.settings.as_ref().map(|settings| settings.revision), Some(3));
+		config.update_after_sync(SyncResponse {
+			settings: Some(remote(18, 4)),
+			acknowledged: Some(request.change_ids),
+			..Default::default()
+		}).unwrap();
+		assert!(!config.has_unsynced_changes());
+		assert!(config.settings_base_revision.is_none());
+		assert_eq!(config.settings_revision, 4);
+
+		// settings which have never been synced are uploaded rather than replaced
+		let mut config = Config::default();
+		config.data.defaults.length = 14;
+		config.update_after_sync(SyncResponse { settings: Some(Settings::default()), ..Default::default() }).unwrap();
+		assert_eq!(config.sync_request(false).settings.map(|settings| settings.defaults.length), Some(14));
+	}
+
+	#[test]
+	fn test_apps() {
+		let mut config = Config::default();
+		assert_eq!(config.extract_domain("android://hash@com.example.android").as_deref(), Some("example.com"));
+		config.set_app_domain("com.example.android", "Example.org").unwrap();
+		assert_eq!(config.extract_domain("android://hash@com.example.android").as_deref(), Some("example.org"));
+		assert_eq!(config.extract_domain("com.example.android").as_deref(), Some("example.org"));
+		assert_eq!(config.app_list().collect::<Vec<_>>(), vec![("com.example.android", "example.org")]);
+
+		config.remove_app("android-app://com.example.android").unwrap();
+		assert_eq!(config.extract_domain("android://hash@com.example.android").as_deref(), Some("example.com"));
+		assert_eq!(config.extract_domain("com.example.android").as_deref(), Some("example.com"));
+		assert!(config.remove_app("com.example.android").is_err());
+	}
+
+	#[test]
+	fn test_inherited() {
+		let mut config = Config::default();
+		config.add("corp.example.com".to_owned(), DomainConfig::default().with_length(16)).unwrap();
+		config.rotate("corp.example.com", 100).unwrap();
+		config.add("*.dev.corp.example.com".to_owned(), DomainConfig::default().with_length(20)).unwrap();
+		assert!(config.add("a.*.example.com".to_owned(), DomainConfig::default()).is_err());
+
+		let vpn = config.for_domain("vpn.corp.example.com");
+		assert_eq!(vpn.inherited_from(), Some("corp.example.com"));
+		assert_eq!(vpn.as_ref().length, 16);
+		assert_eq!(vpn.as_ref().rotation, 0);
+
+		let dev = config.for_domain("a.b.dev.corp.example.com");
+		assert_eq!(dev.inherited_from(), Some("*.dev.corp.example.com"));
+		assert_eq!(dev.as_ref().length, 20);
+		assert_eq!(config.for_domain("dev.corp.example.com").inherited_from(), Some("corp.example.com"));
+
+		assert!(config.for_domain("corp.example.com").inherited_from().is_none());
+		assert!(matches!(config.for_domain("example.com"), Defaulted::Default(_)));
+	}
+
+	#[test]
+	fn test_rotate() {
+		let mut config = Config::default();
+		assert_eq!(config.rotate("example.com", 100).unwrap(), 1);
+		assert_eq!(config.rotate("example.com", 200).unwrap(), 2);
+		let domain_config = config.for_domain("example.com").explicit().unwrap();
+		assert_eq!(domain_config.rotations, vec![
+			Rotation { rotation: 1, timestamp: 100, extra: Default::default() },
+			Rotation { rotation: 2, timestamp: 200, extra: Default::default() },
+		]);
+	}
+
+	#[test]
+	fn test_truncated_lengths_are_clamped() {
+		let config = Config::deserialize(r#"{
+			"defaults": { "length": 30 },
+			"domains": {
+				"example.com": { "length": 30 },
+				"example.org": { "length": 30, "algorithm": "sha512-sgp" }
+			}
+		}"#).unwrap();
+		assert!(config.dirty);
+		assert_eq!(config.defaults.length, 24);
+		assert_eq!(config.for_domain("example.com").as_ref().length, 24);
+		assert_eq!(config.for_domain("example.org").as_ref().length, 30);
+
+		// only files from before lengths were clamped
+		let config = Config::deserialize(&format!(r#"{{
+			"version": {},
+			"defaults": {{ "length": 30 }},
+			"domains": {{ "example.com": {{ "length": 30 }} }}
+		}}"#, CONFIG_VERSION)).unwrap();
+		assert!(!config.dirty);
+		assert_eq!(config.defaults.length, 30);
+		assert_eq!(config.for_domain("example.com").as_ref().length, 30);
+
+		let mut config = Config::default();
+		config.add("example.net".to_owned(), DomainConfig::default().with_length(30)).unwrap();
+		let config = Config::deserialize(&config.serialize().unwrap()).unwrap();
+		assert!(!config.dirty);
+		assert_eq!(config.for_domain("example.net").as_ref().length, 30);
 	}
 }
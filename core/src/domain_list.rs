@@ -1,4 +1,6 @@
-pub const SECOND_LEVEL_DOMAINS: &'static [&'static str; 1018] = &[
+// The second level domains known before the full public suffix list was used.
+// Only used by `DomainExtractor::extract_legacy`.
+pub const SECOND_LEVEL_DOMAINS: &[&str; 1018] = &[
 	"org.ma",
 	"net.pl",
 	"org.mm",
@@ -0,0 +1,147 @@
+use anyhow::*;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use serde::{Serialize, Deserialize, Deserializer, Serializer};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::password::argon2_params;
+
+pub const SALT_LENGTH: usize = 16;
+pub const NONCE_LENGTH: usize = 24;
+
+// binds ciphertexts to their purpose (and salt), so they can't be reused elsewhere
+const ASSOCIATED_DATA: &[u8] = b"passe-vault:";
+
+/// The key used to encrypt synced data, derived from the sync passphrase.
+/// It never leaves the client.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SyncKey {
+	#[serde(with = "base64_bytes")]
+	salt: Vec<u8>,
+
+	#[serde(with = "base64_bytes")]
+	key: Vec<u8>,
+}
+
+impl std::fmt::Debug for SyncKey {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str("SyncKey(<redacted>)")
+	}
+}
+
+impl Drop for SyncKey {
+	fn drop(&mut self) {
+		self.key.zeroize();
+	}
+}
+
+/// Data encrypted with a `SyncKey`, along with the salt needed to derive the key again
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Sealed {
+	#[serde(with = "base64_bytes")]
+	pub salt: Vec<u8>,
+
+	#[serde(with = "base64_bytes")]
+	pub nonce: Vec<u8>,
+
+	#[serde(with = "base64_bytes")]
+	pub ciphertext: Vec<u8>,
+}
+
+impl SyncKey {
+	pub fn new(passphrase: &str, salt: [u8; SALT_LENGTH]) -> Result<Self> {
+		Self::derive(passphrase, &salt)
+	}
+
+	/// The key for existing data, which fails if `passphrase` isn't the one it was sealed with
+	pub fn for_sealed(passphrase: &str, sealed: &Sealed) -> Result<Self> {
+		let salt = <&[u8; SALT_LENGTH]>::try_from(sealed.salt.as_slice())
+			.map_err(|_| anyhow!("Invalid salt length: {}", sealed.salt.len()))?;
+		let key = Self::derive(passphrase, salt)?;
+		key.open(sealed)?;
+		Ok(key)
+	}
+
+	fn derive(passphrase: &str, salt: &[u8; SALT_LENGTH]) -> Result<Self> {
+		let argon = argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, argon2_params());
+		let mut key = vec![0u8; 32];
+		argon.hash_password_into(passphrase.as_bytes(), salt, &mut key)
+			.map_err(|err| anyhow!("Deriving the sync key: {}", err))?;
+		Ok(Self { salt: salt.into(), key })
+	}
+
+	fn cipher(&self) -> XChaCha20Poly1305 {
+		XChaCha20Poly1305::new_from_slice(&self.key).expect("invalid key length")
+	}
+
+	fn associated_data(salt: &[u8]) -> Vec<u8> {
+		[ASSOCIATED_DATA, salt].concat()
+	}
+
+	/// Encrypt `plaintext`. The nonce must be random, and never reused.
+	pub fn seal(&self, plaintext: &[u8], nonce: [u8; NONCE_LENGTH]) -> Sealed {
+		let aad = Self::associated_data(&self.salt);
+		let ciphertext = self.cipher()
+			.encrypt(XNonce::from_slice(&nonce), Payload { msg: plaintext, aad: &aad })
+			.expect("encryption failed");
+		Sealed { salt: self.salt.clone(), nonce: nonce.into(), ciphertext }
+	}
+
+	pub fn open(&self, sealed: &Sealed) -> Result<Zeroizing<Vec<u8>>> {
+		if sealed.nonce.len() != NONCE_LENGTH {
+			bail!("Invalid nonce length: {}", sealed.nonce.len());
+		}
+		if sealed.salt != self.salt {
+			bail!("The synced data was encrypted with a different sync passphrase");
+		}
+		let aad = Self::associated_data(&sealed.salt);
+		let plaintext = self.cipher()
+			.decrypt(XNonce::from_slice(&sealed.nonce), Payload { msg: &sealed.ciphertext, aad: &aad })
+			.map_err(|_| anyhow!("Unable to decrypt the synced data; the sync passphrase may be incorrect"))?;
+		Ok(Zeroizing::new(plaintext))
+	}
+}
+
+// byte arrays are stored as base64, rather than the default JSON array of numbers
+mod base64_bytes {
+	use super::*;
+
+	pub fn serialize<S: Serializer>(bytes: &[u8], s: S) -> Result<S::Ok, S::Error> {
+		STANDARD.encode(bytes).serialize(s)
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+		let encoded = String::deserialize(d)?;
+		STANDARD.decode(encoded).map_err(serde::de::Error::custom)
+	}
+}
+
+#[cfg(test)]
+pub mod test {
+	use super::*;
+
+	#[test]
+	fn test_seal() {
+		let key = SyncKey::new("passphrase", [1; SALT_LENGTH]).unwrap();
+		let sealed = key.seal(b"secret.com", [2; NONCE_LENGTH]);
+		assert_eq!(&**key.open(&sealed).unwrap(), b"secret.com");
+		assert_ne!(sealed.ciphertext, key.seal(b"secret.com", [3; NONCE_LENGTH]).ciphertext);
+
+		let json = serde_json::to_string(&sealed).unwrap();
+		assert!(!json.contains("secret"), "{}", json);
+		assert_eq!(serde_json::from_str::<Sealed>(&json).unwrap(), sealed);
+
+		assert_eq!(SyncKey::for_sealed("passphrase", &sealed).unwrap(), key);
+		assert!(SyncKey::for_sealed("passphrse", &sealed).is_err());
+		assert!(SyncKey::new("passphrase", [4; SALT_LENGTH]).unwrap().open(&sealed).is_err());
+
+		// a short salt (e.g. from a corrupted vault) is an error rather than a panic
+		assert!(SyncKey::for_sealed("passphrase", &Sealed { salt: vec![1; 4], ..sealed.clone() }).is_err());
+
+		let mut tampered = sealed.clone();
+		tampered.ciphertext[0] ^= 1;
+		assert!(key.open(&tampered).is_err());
+	}
+}
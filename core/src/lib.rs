@@ -1,5 +1,11 @@
 pub mod password;
 pub mod config;
+pub mod policy;
 pub mod auth;
+pub mod sync;
+pub mod schema;
+pub mod encryption;
+pub mod verifier;
+pub mod public_suffix;
 pub mod domain_list;
 pub mod domain_extractor;
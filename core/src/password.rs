@@ -1,106 +1,599 @@
 use log::*;
+use std::borrow::Cow;
+use std::fmt;
+use std::collections::BTreeSet;
+use std::str::FromStr;
+use anyhow::anyhow;
 use base64::{Engine, alphabet, engine};
+use serde::{Serialize, Deserialize};
+use sha2::{Sha512, Digest};
+use zeroize::{Zeroize, Zeroizing};
 
 use crate::config::DomainConfig;
+use crate::domain_extractor::normalize_domain;
+use crate::policy::CharClass;
 
 #[derive(Clone, Debug, Copy)]
 pub struct Domain<'a>(pub &'a str);
 
+/// A borrowed master password. Its owner is responsible for
+/// wiping it, e.g. by holding it in a `Zeroizing<String>`.
+#[derive(Clone, Copy)]
 pub struct Password<'a>(pub &'a str);
 
+impl fmt::Debug for Password<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str("Password(<redacted>)")
+	}
+}
+
+const MAX_ITERATIONS: usize = 50;
+
+// The characters which can appear in legacy SGP output (after substitution)
+const LEGACY_CHARS: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GenerateError {
+	/// The domain's policy can never be satisfied, e.g. its alphabet is empty
+	/// or lacks a required character class.
+	InvalidPolicy(String),
+
+	/// No valid password was found, even after applying the fallback.
+	NoValidPassword { iterations: usize },
+}
+
+impl fmt::Display for GenerateError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			GenerateError::InvalidPolicy(reason) => write!(f, "Invalid password policy: {}", reason),
+			GenerateError::NoValidPassword { iterations } =>
+				write!(f, "Didn't find a valid password after {} iterations", iterations),
+		}
+	}
+}
+
+impl std::error::Error for GenerateError {}
+
+/// Selects the `Generator` used for a domain.
+/// New variants must never change the output of existing ones,
+/// since stored domains rely on producing the same password forever.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Algorithm {
+	/// SuperGenPass-compatible iterated MD5
+	#[default]
+	Md5Sgp,
+
+	/// SuperGenPass-style iteration using SHA-512
+	Sha512Sgp,
+
+	/// Argon2id key derivation, followed by SHA-512 SGP iteration
+	Argon2id,
+}
+
+impl Algorithm {
+	pub const ALL: [Algorithm; 3] = [Algorithm::Md5Sgp, Algorithm::Sha512Sgp, Algorithm::Argon2id];
+
+	pub fn is_default(&self) -> bool {
+		*self == Self::default()
+	}
+
+	pub fn name(&self) -> &'static str {
+		match self {
+			Algorithm::Md5Sgp => "md5-sgp",
+			Algorithm::Sha512Sgp => "sha512-sgp",
+			Algorithm::Argon2id => "argon2id",
+		}
+	}
+
+	/// The length of a single encoded digest. Before longer passwords
+	/// were supported, legacy output was silently truncated to this length.
+	pub fn encoded_length(&self) -> usize {
+		match self {
+			Algorithm::Md5Sgp => 24,
+			Algorithm::Sha512Sgp | Algorithm::Argon2id => 88,
+		}
+	}
+
+	fn generator(self) -> &'static dyn Generator {
+		match self {
+			Algorithm::Md5Sgp => &Md5Sgp,
+			Algorithm::Sha512Sgp => &Sha512Sgp,
+			Algorithm::Argon2id => &Argon2idSgp,
+		}
+	}
+}
+
+impl fmt::Display for Algorithm {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(self.name())
+	}
+}
+
+impl FromStr for Algorithm {
+	type Err = anyhow::Error;
+
+	fn from_str(s: &str) -> anyhow::Result<Self> {
+		Self::ALL.into_iter()
+			.find(|alg| alg.name() == s)
+			.ok_or_else(|| anyhow!("Unknown algorithm: {} (expected one of: {})",
+				s, Self::ALL.map(|alg| alg.name()).join(", ")))
+	}
+}
+
+/// The hashing strategy behind an `Algorithm`.
+/// `Gen` repeatedly digests and base64-encodes its state until
+/// it finds a valid password.
+trait Generator: Sync {
+	/// Transform the raw input into the initial state.
+	fn seed(&self, input: &[u8]) -> Zeroizing<Vec<u8>> {
+		Zeroizing::new(input.to_vec())
+	}
+
+	fn digest(&self, value: &[u8]) -> Zeroizing<Vec<u8>>;
+}
+
+struct Md5Sgp;
+impl Generator for Md5Sgp {
+	fn digest(&self, value: &[u8]) -> Zeroizing<Vec<u8>> {
+		let mut digest = md5::compute(value).0;
+		let result = Zeroizing::new(digest.to_vec());
+		digest.zeroize();
+		result
+	}
+}
+
+struct Sha512Sgp;
+impl Generator for Sha512Sgp {
+	fn digest(&self, value: &[u8]) -> Zeroizing<Vec<u8>> {
+		let mut digest = Sha512::digest(value);
+		let result = Zeroizing::new(digest.to_vec());
+		digest.as_mut_slice().zeroize();
+		result
+	}
+}
+
+/// The argon2id parameters for generated passwords, verifiers and sync keys.
+/// Changing them changes every password generated with `Algorithm::Argon2id`.
+pub(crate) fn argon2_params() -> argon2::Params {
+	// OWASP recommended minimum: 19 MiB, 2 iterations
+	argon2::Params::new(19 * 1024, 2, 1, Some(32)).expect("invalid argon2 params")
+}
+
+struct Argon2idSgp;
+impl Argon2idSgp {
+	// A generated password has no per-user state to draw a random salt from,
+	// so the salt is fixed. The input already contains the domain.
+	const SALT: &'static [u8] = b"passe-argon2id-v1";
+}
+
+impl Generator for Argon2idSgp {
+	fn seed(&self, input: &[u8]) -> Zeroizing<Vec<u8>> {
+		let argon = argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, argon2_params());
+		let mut output = Zeroizing::new(vec![0u8; 32]);
+		argon.hash_password_into(input, Self::SALT, &mut output).expect("argon2 failed");
+		output
+	}
+
+	fn digest(&self, value: &[u8]) -> Zeroizing<Vec<u8>> {
+		Sha512Sgp.digest(value)
+	}
+}
+
+/// Output characters for a domain with a non-empty `Policy`
+struct Charset {
+	chars: Vec<char>,
+	required: BTreeSet<CharClass>,
+}
+
+impl Charset {
+	// Map each byte onto the alphabet, rejecting bytes which would bias
+	// the result towards the start of the alphabet.
+	fn pick(&self, byte: u8) -> Option<char> {
+		let len = self.chars.len();
+		let limit = 256 - (256 % len);
+		if (byte as usize) < limit {
+			Some(self.chars[byte as usize % len])
+		} else {
+			None
+		}
+	}
+}
+
+// Everything derived from the master password is held in `Zeroizing`
+// buffers, which are wiped when dropped.
 struct Gen {
-	value: Vec<u8>,
-	buf: String,
+	value: Zeroizing<Vec<u8>>,
+	buf: Zeroizing<String>,
 	engine: engine::GeneralPurpose,
+	generator: &'static dyn Generator,
 	// r64_config: CustomConfig,
+	// the generated length, excluding `post_suffix`
 	length: usize,
+	// None for the legacy SGP output
+	charset: Option<Charset>,
+	post_suffix: String,
 }
 
 impl Gen {
-	fn new(value: String, config: &DomainConfig) -> Self {
+	fn new(value: &[u8], config: &DomainConfig) -> Result<Self, GenerateError> {
 
 		// NOTE: base64 requires lossless encoding.
 		// Hoever SGP reuses 9 & 8, plus A for padding. See `substitute` function below
 		let alpha = alphabet::Alphabet::try_from(
 			"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789./").unwrap();
 		let engine = engine::GeneralPurpose::new(&alpha, Default::default());
+		let generator = config.algorithm.generator();
+		let charset = if config.policy.is_empty() {
+			None
+		} else {
+			let chars = config.policy.charset();
+			if chars.is_empty() || chars.len() > 256 {
+				return Err(GenerateError::InvalidPolicy(
+					format!("alphabet must contain between 1 and 256 characters, not {}", chars.len())));
+			}
+			let required = config.policy.required_classes(&chars);
+			Some(Charset { chars, required })
+		};
 
-		Self {
-			value: value.into_bytes(),
-			buf: String::new(),
+		let post_suffix = config.post_suffix.clone().unwrap_or_default();
+
+		Ok(Self {
+			value: generator.seed(value),
+			// reserve enough for an encoded digest, so that it's never reallocated
+			buf: Zeroizing::new(String::with_capacity(config.algorithm.encoded_length())),
 			engine,
-			length: config.length,
-		}
+			generator,
+			length: config.length.saturating_sub(post_suffix.chars().count()),
+			charset,
+			post_suffix,
+		})
 	}
 
 	fn substitute(byte: u8) -> u8 {
 		match byte {
-			ch if ch == '=' as u8 => 'A' as u8,
-			ch if ch == '.' as u8 => '9' as u8,
-			ch if ch == '/' as u8 => '8' as u8,
+			b'=' => b'A',
+			b'.' => b'9',
+			b'/' => b'8',
 			ch => ch,
 		}
 	}
 
-	fn chars(&self) -> impl Iterator<Item=char> + '_{
-		self.value.iter().take(self.length).map(|u| *u as char)
+	// Legacy output is the current value, continued with the encodings of
+	// further chained digests when `length` exceeds a single encoded digest.
+	fn chars(&self, result: &mut String) {
+		result.extend(self.value.iter().take(self.length).map(|u| *u as char));
+		let mut block = self.value.clone();
+		let mut buf = Zeroizing::new(String::with_capacity(self.buf.capacity()));
+		while result.len() < self.length {
+			buf.clear();
+			self.engine.encode_string(self.generator.digest(&block), &mut buf);
+			block = Zeroizing::new(buf.bytes().map(Self::substitute).collect());
+			result.extend(block.iter().take(self.length - result.len()).map(|u| *u as char));
+		}
 	}
-	
-	fn valid(&self) -> bool {
-		let leading_lower = self.chars().take(1).any(|ch| ch.is_ascii_lowercase());
-		let has_upper = self.chars().any(|ch| ch.is_ascii_uppercase());
-		let has_digit = self.chars().any(|ch| ch.is_ascii_digit());
-		leading_lower && has_upper && has_digit
+
+	// An endless stream of bytes from a chain of digests of the current value
+	fn stream(&self) -> impl Iterator<Item=u8> + '_ {
+		let mut block = self.value.clone();
+		let mut pos = block.len();
+		std::iter::from_fn(move || {
+			if pos == block.len() {
+				block = self.generator.digest(&block);
+				pos = 0;
+			}
+			pos += 1;
+			Some(block[pos - 1])
+		})
+	}
+
+	// Policy output is drawn from `stream`, since rejected bytes
+	// mean a single digest may not be long enough.
+	fn policy_chars(&self, charset: &Charset, result: &mut String) {
+		result.extend(self.stream().filter_map(|b| charset.pick(b)).take(self.length));
 	}
-	
+
+	fn candidate(&self) -> Zeroizing<String> {
+		// allocated up front, so that growing it doesn't leave copies behind
+		let mut generated = Zeroizing::new(String::with_capacity((self.length + self.post_suffix.chars().count()) * 4));
+		match &self.charset {
+			None => self.chars(&mut generated),
+			Some(charset) => self.policy_chars(charset, &mut generated),
+		};
+		generated.push_str(&self.post_suffix);
+		generated
+	}
+
+	fn valid(&self, candidate: &str) -> bool {
+		match &self.charset {
+			None => {
+				let leading_lower = candidate.chars().take(1).any(|ch| ch.is_ascii_lowercase());
+				let has_upper = candidate.chars().any(|ch| ch.is_ascii_uppercase());
+				let has_digit = candidate.chars().any(|ch| ch.is_ascii_digit());
+				leading_lower && has_upper && has_digit
+			},
+			Some(charset) => charset.required.iter()
+				.all(|class| candidate.chars().any(|ch| class.matches(ch))),
+		}
+	}
+
 	fn iterate(&mut self) {
 		self.buf.clear();
-		let digest = md5::compute(&self.value);
-		self.engine.encode_string(digest.0, &mut self.buf);
-		self.value.clear();
-		for b in self.buf.bytes() {
-			self.value.push(Self::substitute(b))
+		let digest = self.generator.digest(&self.value);
+		self.engine.encode_string(&digest, &mut self.buf);
+		// replacing `value` wipes the previous one
+		self.value = Zeroizing::new(self.buf.bytes().map(Self::substitute).collect());
+	}
+
+	// Overwrite characters of an invalid candidate so that it contains each
+	// required class. Positions and replacements are drawn from `stream`,
+	// so the result is still deterministic. The post-suffix is never modified,
+	// but it can satisfy a required class.
+	fn repair(&self, candidate: &str) -> Result<Zeroizing<String>, GenerateError> {
+		let (available, required): (Vec<char>, Vec<CharClass>) = match &self.charset {
+			None => (LEGACY_CHARS.chars().collect(), vec![CharClass::Upper, CharClass::Digit]),
+			Some(charset) => (charset.chars.clone(), charset.required.iter().copied().collect()),
+		};
+		let mut result: Zeroizing<Vec<char>> = Zeroizing::new(candidate.chars().collect());
+		let mut reserved = vec![false; result.len()];
+		let mut bytes = self.stream().map(|b| b as usize);
+		let mut next_byte = || bytes.next().expect("infinite stream");
+
+		let too_short = || GenerateError::InvalidPolicy(
+			format!("length {} is too short for the required character classes", self.length));
+
+		let mut replace = |result: &mut Vec<char>, reserved: &mut Vec<bool>, pos: Option<usize>, class: CharClass| {
+			let members: Vec<char> = available.iter().copied().filter(|ch| class.matches(*ch)).collect();
+			if members.is_empty() {
+				return Err(GenerateError::InvalidPolicy(format!("alphabet contains no {} characters", class)));
+			}
+			let pos = match pos {
+				Some(pos) => pos,
+				None => {
+					let free: Vec<usize> = (0..self.length).filter(|i| !reserved[*i]).collect();
+					if free.is_empty() {
+						return Err(too_short());
+					}
+					free[next_byte() % free.len()]
+				},
+			};
+			result[pos] = members[next_byte() % members.len()];
+			reserved[pos] = true;
+			Ok(())
+		};
+
+		// legacy output always begins with a lowercase letter
+		if self.charset.is_none() {
+			if self.length == 0 {
+				return Err(too_short());
+			}
+			if result[0].is_ascii_lowercase() {
+				reserved[0] = true;
+			} else {
+				replace(&mut result, &mut reserved, Some(0), CharClass::Lower)?;
+			}
+		}
+
+		for class in required {
+			match (0..result.len()).find(|i| !reserved[*i] && class.matches(result[*i])) {
+				Some(existing) => reserved[existing] = true,
+				None => replace(&mut result, &mut reserved, None, class)?,
+			}
 		}
-		// debug!(" -> {}", String::from_utf8(self.value.clone()).unwrap());
+		let mut repaired = Zeroizing::new(String::with_capacity(candidate.len() * 4));
+		repaired.extend(result.iter());
+		Ok(repaired)
 	}
-	
-	fn run(mut self) -> String {
+
+	fn run(mut self) -> Result<Zeroizing<String>, GenerateError> {
 		let mut i = 0;
 		while i < 10 {
 			i += 1;
 			self.iterate();
 		}
 
-		while !self.valid() {
+		loop {
+			let candidate = self.candidate();
+			if self.valid(&candidate) {
+				return Ok(candidate);
+			}
 			i += 1;
 			debug!("Iteration: {}", i);
-			if i > 50 {
-				panic!("Didn't find a valid password after {} iterations", i);
+			if i > MAX_ITERATIONS {
+				debug!("No valid password after {} iterations, repairing candidate", i);
+				let repaired = self.repair(&candidate)?;
+				return if self.valid(&repaired) {
+					Ok(repaired)
+				} else {
+					Err(GenerateError::NoValidPassword { iterations: i })
+				};
 			}
 			self.iterate();
 		}
-		
-		self.chars().collect()
 	}
 }
 
-pub fn generate(domain: Domain, password: Password, config: &DomainConfig) -> String {
-	let input = format!("{}{}:{}", password.0, config.suffix.as_deref().unwrap_or(""), domain.0);
-	Gen::new(input, config).run()
+/// Generate the password for a domain.
+///
+/// Candidates are generated by iterating the domain's `Algorithm` until one satisfies
+/// the required character classes. If none does within `MAX_ITERATIONS`, the final
+/// candidate is deterministically repaired by overwriting characters at digest-derived
+/// positions with members of each missing class. This only fails if the policy can
+/// never be satisfied.
+///
+/// A `post_suffix` is appended to the generated output, which is shortened so that
+/// the result is still `length` characters long.
+///
+/// A nonzero `rotation` is appended to the input as `#<rotation>`,
+/// so unrotated domains generate the same password as they always have.
+///
+/// The domain is normalized (see `normalize_domain`), unless the config
+/// specifies `generate_as` to keep generating from some other name.
+///
+/// The result is wiped from memory when dropped.
+pub fn generate(domain: Domain, password: Password, config: &DomainConfig) -> Result<Zeroizing<String>, GenerateError> {
+	let domain = match &config.generate_as {
+		Some(name) => Cow::Borrowed(name.as_str()),
+		None => normalize_domain(domain.0),
+	};
+	let suffix = config.suffix.as_deref().unwrap_or("");
+	let rotation = if config.rotation > 0 {
+		format!("#{}", config.rotation)
+	} else {
+		String::new()
+	};
+	let mut input = Zeroizing::new(String::with_capacity(password.0.len() + suffix.len() + 1 + domain.len() + rotation.len()));
+	input.push_str(password.0);
+	input.push_str(suffix);
+	input.push(':');
+	input.push_str(&domain);
+	input.push_str(&rotation);
+	Gen::new(input.as_bytes(), config)?.run()
 }
 
 
 #[cfg(test)]
 pub mod test {
 	use super::*;
+	use crate::policy::Policy;
+
+	fn sample(config: DomainConfig) -> String {
+		generate(Domain("example.org"), Password("secret"), &config).unwrap().to_string()
+	}
 
 	#[test]
 	pub fn test_sample() {
-		let generated = generate(
-			Domain("example.org"),
-			Password("secret"),
-			&DomainConfig::default().with_length(10)
-		);
+		let generated = sample(DomainConfig::default().with_length(10));
 		assert_eq!(generated, "tYb1lyMQLA");
 	}
+
+	#[test]
+	pub fn test_algorithms() {
+		let md5 = sample(DomainConfig::default().with_algorithm(Algorithm::Md5Sgp));
+		let sha = sample(DomainConfig::default().with_algorithm(Algorithm::Sha512Sgp));
+		let argon = sample(DomainConfig::default().with_algorithm(Algorithm::Argon2id));
+		assert_eq!(md5, "tYb1lyMQLA");
+		assert_eq!(sha, "lXQZPP2RD2");
+		assert_eq!(argon, "slAsYY4298");
+	}
+
+	#[test]
+	pub fn test_rotation() {
+		let rotated = |rotation| sample(DomainConfig { rotation, ..Default::default() });
+		assert_eq!(rotated(0), "tYb1lyMQLA");
+		assert_eq!(rotated(1), "dS88WPYT31");
+		assert_ne!(rotated(1), rotated(2));
+	}
+
+	#[test]
+	pub fn test_post_suffix() {
+		let generated = sample(DomainConfig { post_suffix: Some("!".to_owned()), ..Default::default() });
+		assert_eq!(generated, "tYb1lyMQL!");
+
+		// the post-suffix satisfies the required symbol, so the generated part needn't
+		let policy = Policy {
+			required: BTreeSet::from([CharClass::Symbol]),
+			alphabet: Some("abc".to_owned()),
+			..Default::default()
+		};
+		let config = DomainConfig { post_suffix: Some("#1".to_owned()), policy, ..Default::default() };
+		let generated = sample(config);
+		assert_eq!(generated.len(), 10);
+		assert!(generated.ends_with("#1"));
+		assert!(generated[..8].chars().all(|ch| "abc!#$%&*+-=?@^_".contains(ch)));
+	}
+
+	#[test]
+	pub fn test_long_passwords() {
+		let short = sample(DomainConfig::default().with_length(24));
+		let long = sample(DomainConfig::default().with_length(60));
+		assert_eq!(long.len(), 60);
+		assert!(long.starts_with(&short));
+		assert_eq!(long, "tYb1lyMQLA39sveVDWZXYgAAnI8uvn2t5svfa0hgIUQNCQAAJ0nKa86MPVY1");
+
+		for alg in Algorithm::ALL {
+			let long = sample(DomainConfig::default().with_algorithm(alg).with_length(alg.encoded_length() + 1));
+			assert_eq!(long.len(), alg.encoded_length() + 1);
+		}
+	}
+
+	#[test]
+	pub fn test_policy() {
+		let pin = Policy { alphabet: Some("0123456789".to_owned()), ..Default::default() };
+		let generated = sample(DomainConfig::default().with_length(6).with_policy(pin));
+		assert_eq!(generated, "145156");
+
+		let symbols = Policy {
+			required: BTreeSet::from([CharClass::Lower, CharClass::Symbol]),
+			forbidden: Some("aeiou".to_owned()),
+			..Default::default()
+		};
+		let generated = sample(DomainConfig::default().with_length(16).with_policy(symbols));
+		assert_eq!(generated.chars().count(), 16);
+		assert!(generated.chars().any(|ch| CharClass::Symbol.matches(ch)));
+		assert!(!generated.chars().any(|ch| "aeiou".contains(ch)));
+	}
+
+	#[test]
+	pub fn test_fallback() {
+		let policy = Policy {
+			alphabet: Some("ab1".to_owned()),
+			required: BTreeSet::from([CharClass::Lower, CharClass::Digit]),
+			..Default::default()
+		};
+		let config = DomainConfig::default().with_length(2).with_policy(policy);
+		let generated = sample(config.clone());
+		assert!(generated.contains('1'));
+
+		let gen = Gen::new(b"secret:example.org", &config).unwrap();
+		let repaired = gen.repair("aa").unwrap();
+		assert!(gen.valid(&repaired));
+		assert_eq!(repaired, gen.repair("aa").unwrap());
+	}
+
+	#[test]
+	pub fn test_normalized_domain() {
+		let config = DomainConfig::default();
+		let expected = sample(config.clone());
+		assert_eq!(*generate(Domain("EXAMPLE.org"), Password("secret"), &config).unwrap(), expected);
+
+		let generate_as = DomainConfig { generate_as: Some("example.org".to_owned()), ..Default::default() };
+		assert_eq!(*generate(Domain("example.com"), Password("secret"), &generate_as).unwrap(), expected);
+	}
+
+	#[test]
+	pub fn test_password_debug_is_redacted() {
+		assert!(!format!("{:?}", Password("secret")).contains("secret"));
+	}
+
+	#[test]
+	pub fn test_invalid_policy() {
+		let generate_with = |policy: Policy, length: usize| {
+			generate(Domain("example.org"), Password("secret"), &DomainConfig::default().with_length(length).with_policy(policy))
+		};
+		let symbol = Policy {
+			alphabet: Some("abc".to_owned()),
+			forbidden: Some("abc".to_owned()),
+			..Default::default()
+		};
+		assert!(matches!(generate_with(symbol, 10), Err(GenerateError::InvalidPolicy(_))));
+
+		let digits = Policy {
+			alphabet: Some("abc".to_owned()),
+			required: BTreeSet::from([CharClass::Digit]),
+			..Default::default()
+		};
+		assert!(matches!(generate_with(digits, 10), Err(GenerateError::InvalidPolicy(_))));
+
+		let all = Policy { required: BTreeSet::from(CharClass::ALL), ..Default::default() };
+		assert!(matches!(generate_with(all, 3), Err(GenerateError::InvalidPolicy(_))));
+	}
+
+	#[test]
+	pub fn test_algorithm_names() {
+		for alg in Algorithm::ALL {
+			assert_eq!(alg, alg.name().parse().unwrap());
+			assert_eq!(serde_json::to_string(&alg).unwrap(), format!("\"{}\"", alg.name()));
+		}
+	}
 }
@@ -0,0 +1,190 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+use anyhow::*;
+use serde::{Serialize, Deserialize};
+
+use crate::config::Extra;
+
+const DEFAULT_ALPHABET: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+// Added when a symbol is required but the alphabet doesn't contain any
+const DEFAULT_SYMBOLS: &str = "!#$%&*+-=?@^_";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum CharClass {
+	Lower,
+	Upper,
+	Digit,
+	Symbol,
+}
+
+impl CharClass {
+	pub const ALL: [CharClass; 4] = [CharClass::Lower, CharClass::Upper, CharClass::Digit, CharClass::Symbol];
+
+	pub fn name(&self) -> &'static str {
+		match self {
+			CharClass::Lower => "lower",
+			CharClass::Upper => "upper",
+			CharClass::Digit => "digit",
+			CharClass::Symbol => "symbol",
+		}
+	}
+
+	pub fn matches(&self, ch: char) -> bool {
+		match self {
+			CharClass::Lower => ch.is_lowercase(),
+			CharClass::Upper => ch.is_uppercase(),
+			CharClass::Digit => ch.is_ascii_digit(),
+			CharClass::Symbol => !(ch.is_alphanumeric() || ch.is_whitespace()),
+		}
+	}
+
+	/// Parse a comma or space separated list, e.g. "lower, digit"
+	pub fn parse_list(s: &str) -> Result<BTreeSet<CharClass>> {
+		s.split(|ch: char| ch == ',' || ch.is_whitespace())
+			.filter(|part| !part.is_empty())
+			.map(CharClass::from_str)
+			.collect()
+	}
+
+	pub fn format_list(classes: &BTreeSet<CharClass>) -> String {
+		classes.iter().map(|c| c.name()).collect::<Vec<_>>().join(", ")
+	}
+}
+
+impl fmt::Display for CharClass {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(self.name())
+	}
+}
+
+impl FromStr for CharClass {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		Self::ALL.into_iter()
+			.find(|c| c.name() == s)
+			.ok_or_else(|| anyhow!("Unknown character class: {} (expected one of: {})",
+				s, Self::ALL.map(|c| c.name()).join(", ")))
+	}
+}
+
+/// Restrictions on the characters of a generated password.
+/// An empty policy keeps the legacy SGP output (base64 alphabet,
+/// leading lowercase plus an uppercase letter and a digit).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct Policy {
+	/// Classes which must appear at least once.
+	/// When empty, defaults to lower, upper and digit (where the alphabet allows)
+	#[serde(default)]
+	#[serde(skip_serializing_if = "BTreeSet::is_empty")]
+	pub required: BTreeSet<CharClass>,
+
+	/// Replaces the default alphanumeric output alphabet
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub alphabet: Option<String>,
+
+	/// Extra characters added to the alphabet
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub allowed: Option<String>,
+
+	/// Characters removed from the alphabet
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub forbidden: Option<String>,
+
+	#[serde(flatten)]
+	pub extra: Extra,
+}
+
+fn non_empty(s: &Option<String>) -> Option<&str> {
+	s.as_deref().filter(|s| !s.is_empty())
+}
+
+impl Policy {
+	pub fn is_empty(&self) -> bool {
+		self.required.is_empty()
+			&& non_empty(&self.alphabet).is_none()
+			&& non_empty(&self.allowed).is_none()
+			&& non_empty(&self.forbidden).is_none()
+			&& self.extra.is_empty()
+	}
+
+	pub fn print(&self) {
+		if !self.required.is_empty() {
+			println!("Required: {}", CharClass::format_list(&self.required));
+		}
+		if let Some(alphabet) = non_empty(&self.alphabet) {
+			println!("Alphabet: {}", alphabet);
+		}
+		if let Some(allowed) = non_empty(&self.allowed) {
+			println!("Allowed: {}", allowed);
+		}
+		if let Some(forbidden) = non_empty(&self.forbidden) {
+			println!("Forbidden: {}", forbidden);
+		}
+	}
+
+	/// The distinct output characters, in a stable order
+	pub fn charset(&self) -> Vec<char> {
+		let forbidden = non_empty(&self.forbidden).unwrap_or("");
+		let mut candidates: Vec<char> = non_empty(&self.alphabet).unwrap_or(DEFAULT_ALPHABET).chars()
+			.chain(non_empty(&self.allowed).unwrap_or("").chars())
+			.collect();
+		if self.required.contains(&CharClass::Symbol) && !candidates.iter().any(|ch| CharClass::Symbol.matches(*ch)) {
+			candidates.extend(DEFAULT_SYMBOLS.chars());
+		}
+
+		let mut result = Vec::new();
+		for ch in candidates {
+			if !forbidden.contains(ch) && !result.contains(&ch) {
+				result.push(ch);
+			}
+		}
+		result
+	}
+
+	/// The effective required classes for the given charset
+	pub fn required_classes(&self, charset: &[char]) -> BTreeSet<CharClass> {
+		if self.required.is_empty() {
+			[CharClass::Lower, CharClass::Upper, CharClass::Digit].into_iter()
+				.filter(|class| charset.iter().any(|ch| class.matches(*ch)))
+				.collect()
+		} else {
+			self.required.clone()
+		}
+	}
+}
+
+#[cfg(test)]
+pub mod test {
+	use super::*;
+
+	#[test]
+	fn test_charset() {
+		let pin = Policy { alphabet: Some("0123456789".to_owned()), ..Default::default() };
+		assert_eq!(pin.charset().len(), 10);
+		assert_eq!(pin.required_classes(&pin.charset()), BTreeSet::from([CharClass::Digit]));
+
+		let symbols = Policy {
+			required: BTreeSet::from([CharClass::Symbol]),
+			forbidden: Some("!#".to_owned()),
+			..Default::default()
+		};
+		let charset = symbols.charset();
+		assert_eq!(charset.len(), 62 + DEFAULT_SYMBOLS.len() - 2);
+		assert!(!charset.contains(&'!'));
+
+		assert!(Policy { alphabet: Some("".to_owned()), ..Default::default() }.is_empty());
+	}
+
+	#[test]
+	fn test_parse_classes() {
+		assert_eq!(CharClass::parse_list("lower, digit").unwrap(), BTreeSet::from([CharClass::Lower, CharClass::Digit]));
+		assert!(CharClass::parse_list("lower,nope").is_err());
+	}
+}
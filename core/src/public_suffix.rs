@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+// https://publicsuffix.org/list/public_suffix_list.dat
+const PUBLIC_SUFFIX_LIST: &str = include_str!("public_suffix_list.dat");
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Rule {
+	// e.g. `co.uk`
+	normal: bool,
+
+	// `*.ck`, stored under `ck`
+	wildcard: bool,
+
+	// `!www.ck`, stored under `www.ck`
+	exception: bool,
+}
+
+/// Public suffix rules, in the standard `.dat` format (including private domains).
+#[derive(Debug, Default)]
+pub struct PublicSuffixList {
+	rules: HashMap<String, Rule>,
+}
+
+impl PublicSuffixList {
+	pub fn parse(dat: &str) -> Self {
+		let mut rules: HashMap<String, Rule> = HashMap::new();
+		for line in dat.lines() {
+			let rule = match line.split_whitespace().next() {
+				Some(rule) if !rule.starts_with("//") => rule,
+				_ => continue,
+			};
+			if let Some(exception) = rule.strip_prefix('!') {
+				rules.entry(exception.to_owned()).or_default().exception = true;
+			} else if let Some(parent) = rule.strip_prefix("*.") {
+				rules.entry(parent.to_owned()).or_default().wildcard = true;
+			} else {
+				rules.entry(rule.to_owned()).or_default().normal = true;
+			}
+		}
+		Self { rules }
+	}
+
+	/// The list embedded at build time
+	pub fn embedded() -> &'static Self {
+		static LIST: OnceLock<PublicSuffixList> = OnceLock::new();
+		LIST.get_or_init(|| Self::parse(PUBLIC_SUFFIX_LIST))
+	}
+
+	// The number of labels in the public suffix of a host,
+	// given its suffixes (shortest first)
+	fn suffix_labels(&self, suffixes: &[&str]) -> usize {
+		// unlisted TLDs are public suffixes (the implicit `*` rule)
+		let mut labels = 1;
+		for (i, suffix) in suffixes.iter().enumerate() {
+			let Some(rule) = self.rules.get(*suffix) else { continue };
+			if rule.exception {
+				// exceptions always win, and exclude their leftmost label
+				return i;
+			}
+			if rule.normal {
+				labels = labels.max(i + 1);
+			}
+			if rule.wildcard && i + 1 < suffixes.len() {
+				labels = labels.max(i + 2);
+			}
+		}
+		labels
+	}
+
+	/// The public suffix plus one label, e.g. `mydomain.co.uk` for `www.mydomain.co.uk`.
+	/// Returns the whole host if it's a public suffix itself.
+	pub fn registrable_domain<'a>(&self, host: &'a str) -> &'a str {
+		let suffixes = Self::suffixes(host);
+		suffixes.get(self.suffix_labels(&suffixes)).copied().unwrap_or(host)
+	}
+
+	/// Whether `host` is a public suffix itself, e.g. `co.uk`
+	pub fn is_public_suffix(&self, host: &str) -> bool {
+		let suffixes = Self::suffixes(host);
+		self.suffix_labels(&suffixes) >= suffixes.len()
+	}
+
+	// e.g. `uk`, `co.uk`, `www.co.uk`
+	fn suffixes(host: &str) -> Vec<&str> {
+		host.rmatch_indices('.')
+			.map(|(idx, _)| &host[idx + 1..])
+			.chain(std::iter::once(host))
+			.collect()
+	}
+}
+
+#[cfg(test)]
+pub mod test {
+	use super::*;
+
+	#[test]
+	fn test_rules() {
+		let list = PublicSuffixList::parse("
+			// comment
+			uk
+			co.uk
+			*.ck
+			!www.ck
+			jp
+			*.kawasaki.jp
+			!city.kawasaki.jp
+		");
+		assert_eq!("example.com", list.registrable_domain("www.example.com"));
+		assert_eq!("example.co.uk", list.registrable_domain("www.example.co.uk"));
+		assert_eq!("co.uk", list.registrable_domain("co.uk"));
+		assert_eq!("example.foo.ck", list.registrable_domain("a.example.foo.ck"));
+		assert_eq!("foo.ck", list.registrable_domain("foo.ck"));
+		assert_eq!("www.ck", list.registrable_domain("a.www.ck"));
+		assert_eq!("example.foo.kawasaki.jp", list.registrable_domain("www.example.foo.kawasaki.jp"));
+		assert_eq!("city.kawasaki.jp", list.registrable_domain("www.city.kawasaki.jp"));
+		assert_eq!("localhost", list.registrable_domain("localhost"));
+
+		assert!(list.is_public_suffix("co.uk"));
+		assert!(list.is_public_suffix("foo.ck"));
+		assert!(list.is_public_suffix("localhost"));
+		assert!(!list.is_public_suffix("example.co.uk"));
+		assert!(!list.is_public_suffix("www.ck"));
+	}
+
+	#[test]
+	fn test_embedded() {
+		let list = PublicSuffixList::embedded();
+		assert_eq!("example.github.io", list.registrable_domain("www.example.github.io"));
+		assert_eq!("example.co.uk", list.registrable_domain("www.example.co.uk"));
+		assert_eq!("www.ck", list.registrable_domain("www.ck"));
+	}
+}
@@ -0,0 +1,127 @@
+use anyhow::*;
+use log::*;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+/// Upgrades a file's JSON from one version to the next
+pub type Upgrade = fn(&mut Value) -> Result<()>;
+
+/// A JSON file format, versioned by a top-level `version` field.
+/// Files without a version predate versioning, and are version 0.
+pub struct Schema {
+	pub name: &'static str,
+
+	// `upgrades[n]` upgrades from version `n` to `n + 1`
+	pub upgrades: &'static [Upgrade],
+}
+
+/// A file's contents, upgraded to the current version
+#[derive(Debug)]
+pub struct Loaded<T> {
+	pub data: T,
+
+	// the file's version, if it was older than the current version
+	pub upgraded_from: Option<u32>,
+}
+
+impl Schema {
+	pub const fn version(&self) -> u32 {
+		self.upgrades.len() as u32
+	}
+
+	/// Parse `contents`, upgrading it from older versions.
+	/// Fails for files written by a newer version.
+	pub fn load<T: DeserializeOwned>(&self, contents: &str) -> Result<Loaded<T>> {
+		let mut value: Value = serde_json::from_str(contents)
+			.with_context(|| format!("Parsing {}", self.name))?;
+		let version = Self::version_of(&value)?;
+		if version > self.version() {
+			bail!("{} is version {}, but this version of passe only supports up to {}. Please upgrade passe.",
+				self.name, version, self.version());
+		}
+
+		for (from, upgrade) in self.upgrades.iter().enumerate().skip(version as usize) {
+			info!("Upgrading {} from version {}", self.name, from);
+			upgrade(&mut value).with_context(|| format!("Upgrading {} from version {}", self.name, from))?;
+		}
+		if let Some(object) = value.as_object_mut() {
+			object.insert("version".to_owned(), self.version().into());
+		}
+
+		let data = serde_json::from_value(value)
+			.with_context(|| format!("Deserializing {}", self.name))?;
+		Ok(Loaded { data, upgraded_from: (version < self.version()).then_some(version) })
+	}
+
+	fn version_of(value: &Value) -> Result<u32> {
+		// only numbers are versions; unversioned files may use `version` as a key
+		// (e.g. a user named "version" in the server's login DB)
+		match value.get("version") {
+			Some(Value::Number(number)) => number.as_u64()
+				.and_then(|version| u32::try_from(version).ok())
+				.ok_or_else(|| anyhow!("Invalid version: {}", number)),
+			_ => Ok(0),
+		}
+	}
+}
+
+/// The `.bak` name for a file's contents before upgrading from `version`,
+/// e.g. `user.json.v0.bak`
+pub fn backup_name(name: &str, version: u32) -> String {
+	format!("{}.v{}.bak", name, version)
+}
+
+/// The version of `backup`, if it's a `backup_name` of `name`
+pub fn backup_version(name: &str, backup: &str) -> Option<u32> {
+	backup.strip_prefix(name)?.strip_prefix(".v")?.strip_suffix(".bak")?.parse().ok()
+}
+
+#[cfg(test)]
+pub mod test {
+	use super::*;
+	use serde_json::json;
+
+	fn add_count(value: &mut Value) -> Result<()> {
+		value["count"] = json!(0);
+		Ok(())
+	}
+
+	fn double_count(value: &mut Value) -> Result<()> {
+		let count = value["count"].as_u64().ok_or_else(|| anyhow!("count missing"))?;
+		value["count"] = json!(count * 2 + 1);
+		Ok(())
+	}
+
+	const SCHEMA: Schema = Schema { name: "test", upgrades: &[add_count, double_count] };
+
+	#[test]
+	fn test_upgrades() {
+		let loaded: Loaded<Value> = SCHEMA.load(r#"{ "a": 1 }"#).unwrap();
+		assert_eq!(loaded.data, json!({ "a": 1, "count": 1, "version": 2 }));
+		assert_eq!(loaded.upgraded_from, Some(0));
+
+		let loaded: Loaded<Value> = SCHEMA.load(r#"{ "version": 1, "count": 3 }"#).unwrap();
+		assert_eq!(loaded.data, json!({ "count": 7, "version": 2 }));
+		assert_eq!(loaded.upgraded_from, Some(1));
+
+		let loaded: Loaded<Value> = SCHEMA.load(r#"{ "version": 2, "count": 3 }"#).unwrap();
+		assert_eq!(loaded.data, json!({ "count": 3, "version": 2 }));
+		assert_eq!(loaded.upgraded_from, None);
+	}
+
+	#[test]
+	fn test_newer_version() {
+		let err = SCHEMA.load::<Value>(r#"{ "version": 3 }"#).unwrap_err();
+		assert!(err.to_string().contains("Please upgrade"), "{}", err);
+		assert!(SCHEMA.load::<Value>(r#"{ "version": -1 }"#).is_err());
+	}
+
+	#[test]
+	fn test_backup_name() {
+		assert_eq!(backup_name("user.json", 2), "user.json.v2.bak");
+		assert_eq!(backup_version("user.json", "user.json.v2.bak"), Some(2));
+		assert_eq!(backup_version("user.json", "user.json.tmp"), None);
+		assert_eq!(backup_version("user.json", "other-user.json.v2.bak"), None);
+		assert_eq!(backup_version("user.json", "user.json.vx.bak"), None);
+	}
+}
@@ -0,0 +1,368 @@
+use serde::{Serialize, Deserialize};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::collections::btree_map::Entry;
+
+use crate::config::{AliasChanges, Aliases, Apps, Change, Changes, ConfigFile, DomainConfig, Domains, Identities};
+use crate::encryption::Sealed;
+use crate::verifier::Verifier;
+
+/// The body of `POST /sync`
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SyncRequest {
+	pub changes: Changes,
+
+	#[serde(default)]
+	pub verifier: Option<Verifier>,
+
+	#[serde(default)]
+	pub identities: Identities,
+
+	#[serde(default)]
+	pub alias_changes: AliasChanges,
+
+	// the revision of each changed domain when the client last synced (0 if it didn't exist).
+	// Changes without one are applied unconditionally, as older clients expect.
+	#[serde(default)]
+	pub base_revisions: Revisions,
+
+	// the `revision` of the client's last sync. If set, the response only
+	// includes domains which have changed since then.
+	#[serde(default)]
+	pub since: Option<u64>,
+
+	#[serde(default)]
+	pub change_ids: ChangeIds,
+
+	// only sent when they've been changed locally
+	#[serde(default)]
+	pub settings: Option<Settings>,
+}
+
+/// The response to `POST /sync`, containing the merged state
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SyncResponse {
+	pub domains: Domains,
+
+	#[serde(default)]
+	pub verifier: Option<Verifier>,
+
+	#[serde(default)]
+	pub identities: Identities,
+
+	#[serde(default)]
+	pub aliases: Aliases,
+
+	// changed domains which weren't applied, because another device changed them first
+	#[serde(default)]
+	pub conflicts: BTreeSet<String>,
+
+	// the server's latest revision, for the next request's `since`
+	#[serde(default)]
+	pub revision: u64,
+
+	// if set, `domains` only contains changes since this revision,
+	// and `deleted` lists the domains deleted since then
+	#[serde(default)]
+	pub since: Option<u64>,
+
+	#[serde(default)]
+	pub deleted: BTreeSet<String>,
+
+	// the ids of the changes which the server has applied (or which were already in effect).
+	// Conflicting changes aren't acknowledged. Older servers don't send this.
+	#[serde(default)]
+	pub acknowledged: Option<ChangeIds>,
+
+	// the server's current settings. Older servers don't sync settings.
+	#[serde(default)]
+	pub settings: Option<Settings>,
+
+	// whether the request's settings weren't applied, because
+	// another device changed them first
+	#[serde(default)]
+	pub settings_conflict: bool,
+}
+
+/// Account-wide settings, which are synced as a whole
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Settings {
+	#[serde(default)]
+	pub defaults: DomainConfig,
+
+	#[serde(default)]
+	pub apps: Apps,
+
+	// the server's revision when these were last changed
+	#[serde(default)]
+	pub revision: u64,
+}
+
+/// Identifies each pending change, so that the server can acknowledge exactly which
+/// ones it applied. A change is given a new id whenever it's modified.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChangeIds {
+	#[serde(default)]
+	pub domains: BTreeMap<String, u64>,
+
+	#[serde(default)]
+	pub aliases: BTreeMap<String, u64>,
+
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub settings: Option<u64>,
+}
+
+impl ChangeIds {
+	pub fn is_empty(&self) -> bool {
+		self.domains.is_empty() && self.aliases.is_empty() && self.settings.is_none()
+	}
+
+	// changes made before ids existed are 0
+	pub fn domain(&self, domain: &str) -> u64 {
+		self.domains.get(domain).copied().unwrap_or(0)
+	}
+
+	pub fn alias(&self, alias: &str) -> u64 {
+		self.aliases.get(alias).copied().unwrap_or(0)
+	}
+}
+
+pub type Revisions = BTreeMap<String, u64>;
+
+/// A user's database, encrypted by the client. The server stores it without
+/// being able to read it, so clients merge their changes into it themselves.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Vault {
+	// incremented by the server for each upload
+	pub revision: u64,
+
+	#[serde(flatten)]
+	pub sealed: Sealed,
+}
+
+/// The body of `POST /vault`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VaultUpload {
+	// the revision of the vault these changes were merged into (0 if there wasn't one).
+	// The upload is rejected if another device has uploaded since.
+	pub base_revision: u64,
+
+	pub sealed: Sealed,
+}
+
+/// The response to `POST /vault`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VaultResponse {
+	// if false, the vault has changed since `base_revision`
+	// and the client should merge its changes again
+	pub accepted: bool,
+
+	pub revision: u64,
+}
+
+/// The result of merging local changes into a vault: the vault to upload,
+/// and the response to apply once the upload is accepted
+#[derive(Debug, Clone)]
+pub struct VaultSync {
+	pub upload: VaultUpload,
+	pub response: SyncResponse,
+}
+
+/// Apply a sync request to the stored database, returning the merged state.
+/// The server does this for plaintext syncs, and clients do it for encrypted ones.
+pub fn apply_request(stored: &mut ConfigFile, request: SyncRequest) -> SyncResponse {
+	let conflicts = apply_changes(&mut stored.domains, &mut stored.deleted, &mut stored.revision, request.changes, &request.base_revisions);
+	let mut settings_conflict = false;
+	if let Some(settings) = request.settings {
+		let mut current = stored.settings();
+		settings_conflict = apply_settings(&mut current, &mut stored.revision, settings);
+		stored.set_settings(current);
+	}
+	let mut acknowledged = request.change_ids;
+	acknowledged.domains.retain(|domain, _| !conflicts.contains(domain));
+	acknowledged.settings = acknowledged.settings.filter(|_| !settings_conflict);
+	stored.changes = Default::default();
+	stored.verifier = newest_verifier(stored.verifier.take(), request.verifier);
+	merge_identities(&mut stored.identities, request.identities);
+	apply_alias_changes(&mut stored.aliases, request.alias_changes);
+	// a client ahead of us must have synced with a different server (or a reset one)
+	let since = request.since.filter(|since| *since <= stored.revision);
+	let (domains, deleted) = match since {
+		Some(since) => changes_since(&stored.domains, &stored.deleted, since),
+		None => (stored.domains.clone(), Default::default()),
+	};
+	SyncResponse {
+		domains,
+		verifier: stored.verifier.clone(),
+		identities: stored.identities.clone(),
+		aliases: stored.aliases.clone(),
+		conflicts,
+		revision: stored.revision,
+		since,
+		deleted,
+		acknowledged: Some(acknowledged),
+		settings: Some(stored.settings()),
+		settings_conflict,
+	}
+}
+
+/// Pick the newest of two verifiers
+pub fn newest_verifier(a: Option<Verifier>, b: Option<Verifier>) -> Option<Verifier> {
+	match (a, b) {
+		(Some(a), Some(b)) => Some(if b.created > a.created { b } else { a }),
+		(a, b) => a.or(b),
+	}
+}
+
+/// Add identities which are missing from `into`, and take the newest verifier
+/// and defaults of those which exist on both sides.
+pub fn merge_identities(into: &mut Identities, from: Identities) {
+	for (name, identity) in from {
+		match into.entry(name) {
+			Entry::Vacant(entry) => { entry.insert(identity); },
+			Entry::Occupied(mut entry) => {
+				let existing = entry.get_mut();
+				existing.verifier = newest_verifier(existing.verifier.take(), identity.verifier);
+				if identity.defaults_updated > existing.defaults_updated {
+					existing.defaults = identity.defaults;
+					existing.defaults_updated = identity.defaults_updated;
+				}
+			},
+		}
+	}
+}
+
+/// Apply changes to stored domains, stamping each with the next `revision`
+/// and recording deletions in `deleted`. Returns the domains whose change
+/// conflicts with a change made since its base revision.
+pub fn apply_changes(domains: &mut Domains, deleted: &mut Revisions, revision: &mut u64, changes: Changes, base_revisions: &Revisions) -> BTreeSet<String> {
+	let mut conflicts = BTreeSet::new();
+	for (domain, change) in changes {
+		let stored = domains.get(&domain);
+		// the same change made on two devices isn't a conflict
+		if is_applied(stored, &change) {
+			continue;
+		}
+		let current = stored.map_or(0, |config| config.revision);
+		if base_revisions.get(&domain).is_some_and(|base| *base != current) {
+			conflicts.insert(domain);
+			continue;
+		}
+		*revision += 1;
+		match change {
+			Change::Delete => {
+				domains.remove(&domain);
+				deleted.insert(domain, *revision);
+			},
+			Change::Set(config) => {
+				deleted.remove(&domain);
+				domains.insert(domain, DomainConfig { revision: *revision, ..config });
+			},
+		}
+	}
+	conflicts
+}
+
+/// Apply changed settings, whose `revision` is the one they were based on.
+/// Returns whether they conflict with a change made since then.
+pub fn apply_settings(stored: &mut Settings, revision: &mut u64, settings: Settings) -> bool {
+	if *stored == (Settings { revision: stored.revision, ..settings.clone() }) {
+		return false;
+	}
+	if settings.revision != stored.revision {
+		return true;
+	}
+	*revision += 1;
+	*stored = Settings { revision: *revision, ..settings };
+	false
+}
+
+/// The domains changed and deleted after revision `since`
+pub fn changes_since(domains: &Domains, deleted: &Revisions, since: u64) -> (Domains, BTreeSet<String>) {
+	let changed = domains.iter()
+		.filter(|(_, config)| config.revision > since)
+		.map(|(domain, config)| (domain.clone(), config.clone()))
+		.collect();
+	let deleted = deleted.iter()
+		.filter(|(_, revision)| **revision > since)
+		.map(|(domain, _)| domain.clone())
+		.collect();
+	(changed, deleted)
+}
+
+fn is_applied(stored: Option<&DomainConfig>, change: &Change<DomainConfig>) -> bool {
+	match (stored, change) {
+		(None, Change::Delete) => true,
+		(Some(stored), Change::Set(config)) => *stored == DomainConfig { revision: stored.revision, ..config.clone() },
+		_ => false,
+	}
+}
+
+/// Apply changes to stored aliases
+pub fn apply_alias_changes(into: &mut Aliases, changes: AliasChanges) {
+	for (alias, change) in changes {
+		match change {
+			Change::Delete => { into.remove(&alias); },
+			Change::Set(canonical) => { into.insert(alias, canonical); },
+		}
+	}
+}
+
+#[cfg(test)]
+pub mod test {
+	use super::*;
+
+	fn set(length: usize) -> Change<DomainConfig> {
+		Change::Set(DomainConfig::default().with_length(length))
+	}
+
+	#[test]
+	fn test_apply_changes() {
+		let mut domains = Domains::new();
+		let mut deleted = Revisions::new();
+		let mut revision = 0;
+		let changes = Changes::from([("a.com".to_owned(), set(12)), ("b.com".to_owned(), set(12))]);
+		assert!(apply_changes(&mut domains, &mut deleted, &mut revision, changes, &Revisions::new()).is_empty());
+		assert_eq!(revision, 2);
+		assert_eq!(domains["b.com"].revision, 2);
+
+		// another device changed a.com since revision 1
+		let changes = Changes::from([("a.com".to_owned(), set(14))]);
+		let base_revisions = Revisions::from([("a.com".to_owned(), 1)]);
+		assert!(apply_changes(&mut domains, &mut deleted, &mut revision, changes.clone(), &base_revisions).is_empty());
+		assert_eq!(domains["a.com"].revision, 3);
+		let changes = Changes::from([("a.com".to_owned(), set(16)), ("b.com".to_owned(), Change::Delete)]);
+		let base_revisions = Revisions::from([("a.com".to_owned(), 1), ("b.com".to_owned(), 2)]);
+		assert_eq!(apply_changes(&mut domains, &mut deleted, &mut revision, changes, &base_revisions), BTreeSet::from(["a.com".to_owned()]));
+		assert_eq!(domains["a.com"].length, 14);
+		assert!(!domains.contains_key("b.com"));
+
+		// making the same change isn't a conflict
+		let changes = Changes::from([("a.com".to_owned(), set(14)), ("b.com".to_owned(), Change::Delete)]);
+		let base_revisions = Revisions::from([("a.com".to_owned(), 1), ("b.com".to_owned(), 1)]);
+		assert!(apply_changes(&mut domains, &mut deleted, &mut revision, changes, &base_revisions).is_empty());
+		assert_eq!(revision, 4);
+		assert_eq!(deleted, Revisions::from([("b.com".to_owned(), 4)]));
+
+		let (changed, removed) = changes_since(&domains, &deleted, 2);
+		assert_eq!(changed.keys().collect::<Vec<_>>(), vec!["a.com"]);
+		assert_eq!(removed, BTreeSet::from(["b.com".to_owned()]));
+		assert!(changes_since(&domains, &deleted, 4).0.is_empty());
+	}
+
+	#[test]
+	fn test_apply_settings() {
+		let mut stored = Settings::default();
+		let mut revision = 3;
+		let settings = |length: usize, revision: u64| Settings { defaults: DomainConfig::default().with_length(length), revision, ..Default::default() };
+
+		assert!(!apply_settings(&mut stored, &mut revision, settings(12, 0)));
+		assert_eq!(stored, settings(12, 4));
+
+		// another device changed them since revision 0
+		assert!(apply_settings(&mut stored, &mut revision, settings(14, 0)));
+		assert!(!apply_settings(&mut stored, &mut revision, settings(12, 0)));
+		assert_eq!(stored, settings(12, 4));
+	}
+}
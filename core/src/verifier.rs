@@ -0,0 +1,115 @@
+use serde::{Serialize, Deserialize};
+use sha2::{Sha512, Digest};
+use subtle::ConstantTimeEq;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::config::Extra;
+use crate::password::{Password, argon2_params};
+
+const SALT_LENGTH: usize = 16;
+
+/// A slow, salted hash of the master password,
+/// used to detect typos before a site password is generated.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Verifier {
+	salt: Vec<u8>,
+	hash: Vec<u8>,
+
+	// seconds since the unix epoch. When syncing, the newest verifier wins
+	pub created: u64,
+
+	#[serde(flatten)]
+	pub extra: Extra,
+}
+
+impl Verifier {
+	pub fn new(password: Password, salt: [u8; SALT_LENGTH], created: u64) -> Self {
+		let hash = Self::hash(password, &salt).to_vec();
+		Self { salt: salt.into(), hash, created, extra: Default::default() }
+	}
+
+	fn hash(password: Password, salt: &[u8]) -> Zeroizing<[u8; 32]> {
+		let argon = argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, argon2_params());
+		let mut output = Zeroizing::new([0u8; 32]);
+		argon.hash_password_into(password.0.as_bytes(), salt, output.as_mut()).expect("argon2 failed");
+		output
+	}
+
+	// compared in constant time, so that timing doesn't reveal how much of the hash matched
+	pub fn verify(&self, password: Password) -> bool {
+		self.hash.ct_eq(Self::hash(password, &self.salt).as_slice()).into()
+	}
+}
+
+const WORDS: [&str; 64] = [
+	"amber", "anchor", "apple", "arrow", "badger", "bamboo", "beacon", "birch",
+	"bison", "bramble", "breeze", "canyon", "cedar", "comet", "copper", "coral",
+	"cricket", "dune", "ember", "falcon", "fern", "fjord", "flint", "fox",
+	"garnet", "glacier", "harbor", "hazel", "heron", "island", "ivy", "jasper",
+	"juniper", "kestrel", "lagoon", "lantern", "lark", "lemon", "lynx", "maple",
+	"meadow", "mesa", "moss", "nutmeg", "oak", "orchid", "otter", "pebble",
+	"pepper", "pine", "quartz", "raven", "reef", "river", "saffron", "sparrow",
+	"spruce", "thistle", "tiger", "tulip", "velvet", "walnut", "willow", "zephyr",
+];
+
+const COLOURS: [(&str, &str); 8] = [
+	("red", "#d9534f"),
+	("orange", "#f0883e"),
+	("yellow", "#e3b341"),
+	("green", "#3fb950"),
+	("teal", "#2aa198"),
+	("blue", "#388bfd"),
+	("purple", "#a371f7"),
+	("pink", "#db61a2"),
+];
+
+/// A short, memorable summary of the typed master password.
+/// It's derived with a fast unsalted hash and carries only 15 bits,
+/// so it confirms a familiar password without revealing much about it.
+#[derive(Clone, Debug, Serialize, PartialEq, Eq)]
+pub struct Fingerprint {
+	pub words: [&'static str; 2],
+	pub colour: &'static str,
+	pub colour_hex: &'static str,
+}
+
+impl Fingerprint {
+	pub fn new(password: Password) -> Self {
+		let mut hasher = Sha512::new();
+		hasher.update(b"passe-fingerprint:");
+		hasher.update(password.0.as_bytes());
+		let mut digest = hasher.finalize();
+		let (colour, colour_hex) = COLOURS[digest[2] as usize % COLOURS.len()];
+		let words = [WORDS[digest[0] as usize % WORDS.len()], WORDS[digest[1] as usize % WORDS.len()]];
+		digest.as_mut_slice().zeroize();
+		Self { words, colour, colour_hex }
+	}
+}
+
+impl std::fmt::Display for Fingerprint {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}-{} ({})", self.words[0], self.words[1], self.colour)
+	}
+}
+
+#[cfg(test)]
+pub mod test {
+	use super::*;
+
+	#[test]
+	fn test_verifier() {
+		let verifier = Verifier::new(Password("secret"), [1; SALT_LENGTH], 0);
+		assert!(verifier.verify(Password("secret")));
+		assert!(!verifier.verify(Password("secrt")));
+		assert_ne!(verifier.hash, Verifier::new(Password("secret"), [2; SALT_LENGTH], 0).hash);
+		// a synced verifier may have a truncated hash
+		let truncated = Verifier { hash: verifier.hash[..16].to_vec(), ..verifier.clone() };
+		assert!(!truncated.verify(Password("secret")));
+	}
+
+	#[test]
+	fn test_fingerprint() {
+		assert_eq!(Fingerprint::new(Password("secret")), Fingerprint::new(Password("secret")));
+		assert_ne!(Fingerprint::new(Password("secret")), Fingerprint::new(Password("secrt")));
+	}
+}
@@ -5,6 +5,7 @@ cd "$(dirname "$2")/.."
 ls -1 *.toml *.lock | sort > "$1"
 find src \
 	-name '*.rs' \
+	-o -name '*.dat' \
 	-o -name '*.toml' \
 	-o -name '*.lock' \
 	| sort \
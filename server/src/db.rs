@@ -6,7 +6,9 @@ use crate::storage::File;
 use base64::engine::general_purpose::STANDARD;
 use base64::engine::Engine;
 use passe_core::auth::*;
-use passe_core::config::{self, Change, ConfigFile};
+use passe_core::config::{self, ConfigFile, CONFIG_SCHEMA};
+use passe_core::schema::{Loaded, Schema};
+use passe_core::sync::{self, SyncRequest, SyncResponse, Vault, VaultResponse, VaultUpload};
 use serde::{Serialize, Deserialize, de::DeserializeOwned};
 use rand::Rng;
 use rand::TryRng;
@@ -78,14 +80,42 @@ impl Token {
 pub struct User {
 	password: Password,
 	tokens: Vec<Token>,
+
+	#[serde(flatten)]
+	extra: config::Extra,
 }
 
 impl User {
 	fn new(password: Password) -> Self {
-		User { password, tokens: Vec::new() }
+		User { password, tokens: Vec::new(), extra: Default::default() }
 	}
 }
 
+// `users.json`
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct LoginFile<Users> {
+	version: u32,
+	users: Users,
+}
+
+const LOGIN_SCHEMA: Schema = Schema {
+	name: "login DB",
+	upgrades: &[wrap_users],
+};
+
+// v0 -> v1: users.json was a bare map of users, with no room for a version
+fn wrap_users(value: &mut serde_json::Value) -> Result<()> {
+	let users = value.take();
+	*value = serde_json::json!({ "users": users });
+	Ok(())
+}
+
+// `vault-<name>.json`, which has no upgrades yet
+const VAULT_SCHEMA: Schema = Schema {
+	name: "vault",
+	upgrades: &[],
+};
+
 fn now() -> Result<EpochSeconds> {
 	let sys = SystemTime::now();
 	let secs = sys.duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
@@ -134,7 +164,8 @@ pub struct UserDB {
 
 impl UserDB {
 	pub fn new<P: Persistence>(persistence: P) -> Result<UserDB> {
-		let users: HashMap<String, User> = Self::load_file(&persistence, File::LoginDB)?;
+		let login_file: LoginFile<HashMap<String, User>> = Self::load_file(&persistence, File::LoginDB, &LOGIN_SCHEMA)?;
+		let users = login_file.users;
 		Ok(Self {
 			users: users.clone(),
 			stored_users: users,
@@ -171,20 +202,49 @@ impl UserDB {
 	}
 	
 	pub fn user_db(&mut self, user: &AuthenticatedUser) -> Result<ConfigFile> {
-		Self::load_file(self.persistence.as_ref(), File::UserDB(user.name()))
+		if self.persistence.load(File::Vault(user.name()))?.is_some() {
+			bail!("This account's data is encrypted. Set a sync passphrase (or upgrade passe) to sync it.");
+		}
+		Self::load_file(self.persistence.as_ref(), File::UserDB(user.name()), &CONFIG_SCHEMA)
 	}
 
-	pub fn sync_changes(&mut self, user: &AuthenticatedUser, client_changes: config::Changes) -> Result<config::Domains> {
+	pub fn sync(&mut self, user: &AuthenticatedUser, request: SyncRequest) -> Result<SyncResponse> {
 		let mut config = self.user_db(user)?;
-		for (domain, change) in client_changes {
-			match change {
-				Change::Delete => { config.domains.remove(&domain); },
-				Change::Set(v) => { config.domains.insert(domain, v); },
-			}
+		let response = sync::apply_request(&mut config, request);
+		if !response.conflicts.is_empty() {
+			info!("Sync conflicts for {}: {:?}", user.name(), &response.conflicts);
 		}
-		config.changes = Default::default();
 		Self::save_file(self.persistence.as_ref(), File::UserDB(user.name()), &config)?;
-		Ok(config.domains)
+		Ok(response)
+	}
+
+	pub fn vault(&mut self, user: &AuthenticatedUser) -> Result<Option<Vault>> {
+		Self::load_file(self.persistence.as_ref(), File::Vault(user.name()), &VAULT_SCHEMA)
+	}
+
+	// Uploads are only stored if they're based on the current vault,
+	// since we can't merge encrypted changes ourselves
+	pub fn upload_vault(&mut self, user: &AuthenticatedUser, upload: VaultUpload) -> Result<VaultResponse> {
+		let current = self.vault(user)?;
+		let revision = current.as_ref().map_or(0, |vault| vault.revision);
+		if upload.base_revision != revision {
+			info!("Rejecting vault upload for {} based on revision {}, which is now {}", user.name(), upload.base_revision, revision);
+			return Ok(VaultResponse { accepted: false, revision });
+		}
+		let vault = Vault { revision: revision + 1, sealed: upload.sealed };
+		Self::save_file(self.persistence.as_ref(), File::Vault(user.name()), &vault)?;
+		if current.is_none() {
+			// the vault replaces any plaintext data, which we shouldn't keep
+			info!("Removing unencrypted data for {}", user.name());
+			self.persistence.remove(File::UserDB(user.name()))?;
+		}
+		Ok(VaultResponse { accepted: true, revision: vault.revision })
+	}
+
+	// legacy sync used by `POST /db`, which only exchanges domains
+	pub fn sync_changes(&mut self, user: &AuthenticatedUser, client_changes: config::Changes) -> Result<config::Domains> {
+		let request = SyncRequest { changes: client_changes, ..Default::default() };
+		Ok(self.sync(user, request)?.domains)
 	}
 
 	fn is_dirty(&self) -> bool {
@@ -193,7 +253,8 @@ impl UserDB {
 
 	fn autosave(&mut self) -> Result<()> {
 		if self.is_dirty() {
-			Self::save_file(self.persistence.as_ref(), File::LoginDB, &self.users)?;
+			let login_file = LoginFile { version: LOGIN_SCHEMA.version(), users: &self.users };
+			Self::save_file(self.persistence.as_ref(), File::LoginDB, &login_file)?;
 			self.stored_users = self.users.clone();
 		}
 		Ok(())
@@ -204,11 +265,20 @@ impl UserDB {
 		persistence.save(file, &serde_json::to_string_pretty(t)?)
 	}
 
-	fn load_file<T, P: Persistence + ?Sized>(persistence: &P, file: File) -> Result<T> where T: DeserializeOwned + Default {
+	// Older files are upgraded in place, keeping a backup
+	fn load_file<T, P: Persistence + ?Sized>(persistence: &P, file: File, schema: &Schema) -> Result<T> where T: Serialize + DeserializeOwned + Default {
 		info!("Loading {:?}", file);
 		let contents = persistence.load(file)?;
 		match contents {
-			Some(contents) => Ok(serde_json::from_str(&contents)?),
+			Some(contents) => {
+				let Loaded { data, upgraded_from } = schema.load(&contents)
+					.with_context(|| format!("Loading {:?}", file))?;
+				if let Some(version) = upgraded_from {
+					persistence.backup(file, version, &contents)?;
+					Self::save_file(persistence, file, &data)?;
+				}
+				Ok(data)
+			},
 			None => Ok(Default::default()),
 		}
 	}
@@ -55,3 +55,23 @@ impl<'r> rocket::request::FromRequest<'r> for AuthenticatedUser {
 		}
 	}
 }
+
+/// The `If-None-Match` header, if present
+pub struct IfNoneMatch(Option<String>);
+
+impl IfNoneMatch {
+	pub fn matches(&self, etag: &str) -> bool {
+		self.0.as_deref().is_some_and(|header| {
+			header.split(',').map(|tag| tag.trim()).any(|tag| tag == "*" || tag == etag)
+		})
+	}
+}
+
+#[async_trait]
+impl<'r> rocket::request::FromRequest<'r> for IfNoneMatch {
+	type Error = std::convert::Infallible;
+
+	async fn from_request(request: &'r rocket::Request<'_>) -> Outcome<Self, Self::Error> {
+		Outcome::Success(IfNoneMatch(request.headers().get_one("if-none-match").map(|s| s.to_owned())))
+	}
+}
@@ -5,6 +5,7 @@ mod db;
 mod storage;
 mod request;
 
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::net::{IpAddr, Ipv4Addr};
 use rocket::http;
 use rocket::State;
@@ -14,6 +15,7 @@ use rocket::fs::{self, FileServer};
 
 use passe_core::auth::{LoginRequest, Authentication};
 use passe_core::config;
+use passe_core::sync::{SyncRequest, SyncResponse, Vault, VaultResponse, VaultUpload};
 
 use crate::error::{HttpResult, HttpError};
 use crate::request::*;
@@ -42,7 +44,7 @@ fn login(data: Json<LoginRequest>, state: &State<DbMutex>) -> Result<Json<Authen
 		http::Status::Unauthorized
 	})?;
 	Result::Ok(Json(Authentication {
-		user: login_request.user, token: token.value
+		user: login_request.user.clone(), token: token.value
 	}))
 }
 
@@ -51,9 +53,28 @@ fn authenticate(user: AuthenticatedUser) -> HttpResult<Json<String>> {
 	Result::Ok(Json(user.name().to_owned()))
 }
 
+#[derive(Responder)]
+enum DbResponse {
+	#[response(status = 304)]
+	NotModified(()),
+	Found(Json<Box<config::ConfigFile>>, http::Header<'static>),
+}
+
 #[get("/db")]
-fn get_db(user: AuthenticatedUser, state: &State<DbMutex>) -> HttpResult<Json<config::ConfigFile>> {
-	Result::Ok(Json(state.lock().user_db(&user)?))
+fn get_db(user: AuthenticatedUser, if_none_match: IfNoneMatch, state: &State<DbMutex>) -> HttpResult<DbResponse> {
+	let db = state.lock().user_db(&user)?;
+	let etag = etag(&db)?;
+	if if_none_match.matches(&etag) {
+		return Result::Ok(DbResponse::NotModified(()));
+	}
+	Result::Ok(DbResponse::Found(Json(Box::new(db)), http::Header::new("ETag", etag)))
+}
+
+// The revision only covers domains, so this hashes the whole file
+fn etag(db: &config::ConfigFile) -> Result<String> {
+	let mut hasher = DefaultHasher::new();
+	serde_json::to_string(db)?.hash(&mut hasher);
+	Ok(format!("\"{}-{:x}\"", db.revision, hasher.finish()))
 }
 
 #[post("/db", data="<data>")]
@@ -61,6 +82,21 @@ fn post_db(user: AuthenticatedUser, data: Json<config::Changes>, state: &State<D
 	Result::Ok(Json(state.lock().sync_changes(&user, data.0)?))
 }
 
+#[post("/sync", data="<data>")]
+fn sync(user: AuthenticatedUser, data: Json<SyncRequest>, state: &State<DbMutex>) -> HttpResult<Json<SyncResponse>> {
+	Result::Ok(Json(state.lock().sync(&user, data.0)?))
+}
+
+#[get("/vault")]
+fn get_vault(user: AuthenticatedUser, state: &State<DbMutex>) -> HttpResult<Json<Option<Vault>>> {
+	Result::Ok(Json(state.lock().vault(&user)?))
+}
+
+#[post("/vault", data="<data>")]
+fn post_vault(user: AuthenticatedUser, data: Json<VaultUpload>, state: &State<DbMutex>) -> HttpResult<Json<VaultResponse>> {
+	Result::Ok(Json(state.lock().upload_vault(&user, data.0)?))
+}
+
 #[launch]
 fn rocket() -> _ {
 	env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
@@ -78,7 +114,10 @@ fn rocket() -> _ {
 			login,
 			authenticate,
 			get_db,
-			post_db
+			post_db,
+			sync,
+			get_vault,
+			post_vault
 		])
 		// these mirror the on-disk layout for consistency, but don't expose anything outside the public folders
 		.mount("/ui/public", FileServer::new(concat!(env!("CARGO_MANIFEST_DIR"), "/../ui/public"),
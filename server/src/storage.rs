@@ -1,18 +1,26 @@
-use std::{path::PathBuf, fs};
+use std::{path::{Path, PathBuf}, fs};
 use std::env;
 
 use anyhow::*;
+use passe_core::schema;
 
 #[derive(Copy, Clone, Debug)]
 pub enum File<'a> {
 	LoginDB,
 	UserDB(&'a str),
+	Vault(&'a str),
 }
 
 pub trait Persistence: std::fmt::Debug + Send + Sync + 'static {
 	fn load(&self, file: File<'_>) -> Result<Option<String>>;
 
 	fn save(&self, file: File<'_>, contents: &str) -> Result<()>;
+
+	// keep the contents of a file from before it was upgraded from `version`
+	fn backup(&self, file: File<'_>, version: u32, contents: &str) -> Result<()>;
+
+	// remove a file along with its backups, e.g. once its contents have been encrypted
+	fn remove(&self, file: File<'_>) -> Result<()>;
 }
 
 #[derive(Debug, Clone)]
@@ -25,14 +33,32 @@ impl FsPersistence {
 		match file {
 			File::LoginDB => base.push("users.json"),
 			File::UserDB(u) => base.push(format!("user-{}.json", u)),
+			File::Vault(u) => base.push(format!("vault-{}.json", u)),
 		}
 		base
 	}
 	
-	fn tmp_path(path: &PathBuf) -> PathBuf {
+	fn tmp_path(path: &Path) -> PathBuf {
 		let filename = path.file_name().map(|p| p.to_str().expect("non-utf8 filename")).unwrap_or_else(|| "");
 		path.with_file_name(format!("{}.tmp", filename))
 	}
+
+	// backups of `path` saved by `backup`, for any version
+	fn backup_paths(path: &Path) -> Result<Vec<PathBuf>> {
+		let filename = path.file_name().map(|p| p.to_str().expect("non-utf8 filename")).unwrap_or_else(|| "");
+		let Some(dir) = path.parent().filter(|dir| dir.exists()) else {
+			return Ok(Vec::new());
+		};
+		let mut result = Vec::new();
+		for entry in fs::read_dir(dir)? {
+			let entry = entry?;
+			let name = entry.file_name();
+			if name.to_str().and_then(|name| schema::backup_version(filename, name)).is_some() {
+				result.push(entry.path());
+			}
+		}
+		Ok(result)
+	}
 }
 
 impl Persistence for FsPersistence {
@@ -53,4 +79,26 @@ impl Persistence for FsPersistence {
 		fs::rename(tmp_path, dest)?;
 		Ok(())
 	}
+
+	fn backup(&self, file: File<'_>, version: u32, contents: &str) -> Result<()> {
+		let path = FsPersistence::path(file);
+		let filename = path.file_name().map(|p| p.to_str().expect("non-utf8 filename")).unwrap_or_else(|| "");
+		let backup = path.with_file_name(schema::backup_name(filename, version));
+		warn!("Saving a backup of {:?} to {:?}", &path, &backup);
+		fs::write(backup, contents)?;
+		Ok(())
+	}
+
+	fn remove(&self, file: File<'_>) -> Result<()> {
+		let path = FsPersistence::path(file);
+		for backup in FsPersistence::backup_paths(&path)? {
+			debug!("Removing backup {:?}", &backup);
+			fs::remove_file(backup)?;
+		}
+		if path.exists() {
+			debug!("Removing file {:?}", &path);
+			fs::remove_file(path)?;
+		}
+		Ok(())
+	}
 }
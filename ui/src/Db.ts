@@ -5,28 +5,112 @@ import { Authentication } from './State.js';
 
 const CACHE_KEY = 'user-db';
 
+const VAULT_ATTEMPTS = 3;
+
+export type Algorithm = 'md5-sgp' | 'sha512-sgp' | 'argon2id'
+
+export const ALGORITHMS: Array<Algorithm> = ['md5-sgp', 'sha512-sgp', 'argon2id']
+
+export const DEFAULT_ALGORITHM: Algorithm = 'md5-sgp'
+
+export type CharClass = 'lower' | 'upper' | 'digit' | 'symbol'
+
+export const CHAR_CLASSES: Array<CharClass> = ['lower', 'upper', 'digit', 'symbol']
+
+export type Policy = {
+	required?: Array<CharClass>,
+	alphabet?: string,
+	allowed?: string,
+	forbidden?: string,
+}
+
+export type Rotation = {
+	rotation: number,
+	timestamp: number,
+}
+
 export type DomainConfig = {
 	length: number,
 	suffix: string|undefined,
+	post_suffix: string|undefined,
+	algorithm: Algorithm|undefined,
+	policy: Policy|undefined,
+	rotation: number|undefined,
+	rotations: Array<Rotation>|undefined,
+	identity: string|undefined,
+	migrated: number|undefined,
+	generate_as: string|undefined,
 	note: string|undefined,
 }
 
 export const DEFAULT_DOMAIN_CONFIG: DomainConfig = {
 	length: 10,
 	suffix: undefined,
+	post_suffix: undefined,
+	algorithm: undefined,
+	policy: undefined,
+	rotation: undefined,
+	rotations: undefined,
+	identity: undefined,
+	migrated: undefined,
+	generate_as: undefined,
 	note: undefined
 }
 
+function policyEq(a: Policy|undefined, b: Policy|undefined): boolean {
+	const required = (p: Policy|undefined) => CHAR_CLASSES.filter(c => (p?.required || []).includes(c)).join(',');
+	return (
+		required(a) == required(b) &&
+			(a?.alphabet || '') == (b?.alphabet || '') &&
+			(a?.allowed || '') == (b?.allowed || '') &&
+			(a?.forbidden || '') == (b?.forbidden || '')
+	)
+}
+
 export function domainConfigEq(a: DomainConfig, b: DomainConfig): boolean {
 	return (
 		a.length === b.length &&
 			(a.suffix || '') == (b.suffix || '') &&
+			(a.post_suffix || '') == (b.post_suffix || '') &&
+			(a.algorithm || DEFAULT_ALGORITHM) == (b.algorithm || DEFAULT_ALGORITHM) &&
+			policyEq(a.policy, b.policy) &&
+			(a.rotation || 0) == (b.rotation || 0) &&
+			(a.identity || '') == (b.identity || '') &&
 			(a.note || '') == (b.note || '')
 	)
 }
 
 type SyncState = 'stale' | 'in-sync'
 
+export type PendingChange = {
+	name: string,
+	kind: 'added' | 'updated' | 'deleted' | 'conflicted' | 'aliased' | 'unaliased',
+}
+
+export type Settings = {
+	defaults: DomainConfig,
+	apps: { [app: string]: string },
+	revision: number,
+}
+
+// a local change which conflicts with a change from another device
+export type Conflict = {
+	domain: string,
+	local: DomainConfig|null,
+	remote: DomainConfig|null,
+}
+
+export type VaultResponse = {
+	accepted: boolean,
+	revision: number,
+}
+
+export type Fingerprint = {
+	words: [string, string],
+	colour: string,
+	colour_hex: string,
+}
+
 export class Db {
 	config: Config;
 	userState: UserState;
@@ -60,11 +144,35 @@ export class Db {
 		this.recomputeOnDbUpdate();
 		return this.config.has_unsynced_changes() ? 'stale' : 'in-sync';
 	}
+
+	pendingChanges(): Array<PendingChange> {
+		this.recomputeOnDbUpdate();
+		return this.config.pending_changes();
+	}
 	
-	generatePassword(domain: string, password: string): string {
-		return this.config.generate_password(domain, password)
+	generatePassword(domain: string, password: string, identity?: string): string {
+		return this.config.generate_password(domain, password, identity)
 	}
 	
+	fingerprint(password: string): Fingerprint {
+		return this.config.fingerprint(password);
+	}
+
+	// null if there's no remembered master password
+	checkMasterPassword(password: string, identity: string|undefined): boolean|null {
+		return this.config.check_master_password(password, identity || undefined) ?? null;
+	}
+
+	rememberMasterPassword(password: string, identity: string|undefined) {
+		this.config.remember_master_password(password, identity || undefined);
+		this.save();
+	}
+
+	identities(): Array<string> {
+		this.recomputeOnDbUpdate();
+		return this.config.identities();
+	}
+
 	private markDbUpdated() {
 		console.log("marking DB as updated");
 		this.userState.invalidateDb += 1;
@@ -88,11 +196,101 @@ export class Db {
 		this.recomputeOnDbUpdate();
 		return this.config.default_config();
 	}
+
+	saveDefaults(config: DomainConfig) {
+		this.config.save_defaults(config);
+		this.save();
+	}
+
+	identityDefaults(identity: string): DomainConfig {
+		this.recomputeOnDbUpdate();
+		return this.config.identity_defaults(identity);
+	}
+
+	saveIdentityDefaults(identity: string, config: DomainConfig) {
+		this.config.save_identity_defaults(identity, config);
+		this.save();
+	}
+
+	// the other device's settings, if they conflict with local changes
+	settingsConflict(): Settings|null {
+		this.recomputeOnDbUpdate();
+		return this.config.settings_conflict() ?? null;
+	}
+
+	resolveSettingsConflict(resolution: 'local' | 'remote') {
+		this.config.resolve_settings_conflict(resolution);
+		this.save();
+	}
+
+	// the config for an unconfigured domain, which may be inherited from a parent
+	defaultConfigFor(domain: string): DomainConfig {
+		this.recomputeOnDbUpdate();
+		return this.config.default_config_for(domain);
+	}
+
+	inheritedFrom(domain: string): string|null {
+		this.recomputeOnDbUpdate();
+		return this.config.inherited_from(domain) ?? null;
+	}
 	
 	saveDomain(domain: string, config: DomainConfig) {
 		this.config.save_domain(domain, config);
 		this.save();
 	}
+
+	renameDomain(from: string, to: string) {
+		this.config.rename_domain(from, to);
+		this.save();
+	}
+
+	conflicts(): Array<Conflict> {
+		this.recomputeOnDbUpdate();
+		return this.config.conflicts();
+	}
+
+	resolveConflict(domain: string, resolution: 'local' | 'remote') {
+		this.config.resolve_conflict(domain, resolution);
+		this.save();
+	}
+
+	deleteDomain(domain: string) {
+		this.config.delete_domain(domain);
+		this.save();
+	}
+
+	// the canonical domain, if `domain` is an alias
+	aliasOf(domain: string): string|null {
+		this.recomputeOnDbUpdate();
+		return this.config.alias_of(domain) ?? null;
+	}
+
+	addAlias(alias: string, canonical: string) {
+		this.config.add_alias(alias, canonical);
+		this.save();
+	}
+
+	removeAlias(alias: string) {
+		this.config.remove_alias(alias);
+		this.save();
+	}
+
+	// domains with duplicate configs (exactly as stored), by normalized name
+	duplicates(): { [domain: string]: Array<string> } {
+		this.recomputeOnDbUpdate();
+		return this.config.duplicates();
+	}
+
+	keepDuplicate(domain: string) {
+		this.config.keep_duplicate(domain);
+		this.save();
+	}
+
+	rotateDomain(domain: string): number {
+		const rotation = this.config.rotate_domain(domain);
+		this.save();
+		return rotation;
+	}
 	
 	tryAuthenticate = () => {
 		const req = this.config.authenticate_request();
@@ -143,16 +341,71 @@ export class Db {
 		})();
 	}
 	
+	usesEncryptedSync(): boolean {
+		this.recomputeOnDbUpdate();
+		return this.config.uses_encrypted_sync();
+	}
+
 	sync = async () => {
+		this.userState.syncTask = this.runSync();
+	}
+
+	// If the account already has a vault, the passphrase must be the one it was encrypted with.
+	// Otherwise it's entered again via `confirm`, since a typo would lock us out of the new vault.
+	setSyncPassphrase = async (passphrase: string, confirm: () => Promise<string>) => {
 		this.userState.syncTask = (async () => {
-			const req = this.config.sync_request();
-			const newDb = await fetchReq<Object>(req);
-			console.log("sync completed");
-			this.config.update_after_sync(newDb);
+			const vault = await fetchReq<Object|null>(this.config.vault_request());
+			if (vault == null && await confirm() !== passphrase) {
+				throw new Error("Passphrases don't match");
+			}
+			if (vault == null && !this.config.uses_encrypted_sync()) {
+				// the server's data is replaced by the first vault, so it must include everything
+				await this.plaintextSync();
+			}
+			this.config.set_sync_passphrase(passphrase, vault);
 			this.save();
-			this.markDbUpdated();
+			await this.runSync();
 		})();
 	}
+
+	private async runSync() {
+		if (this.config.uses_encrypted_sync()) {
+			await this.syncVault();
+		} else {
+			await this.plaintextSync();
+		}
+		console.log("sync completed");
+		this.save();
+		this.markDbUpdated();
+		const conflicts = this.conflicts().map(c => c.domain);
+		if (this.settingsConflict() != null) {
+			conflicts.push('settings');
+		}
+		if (conflicts.length > 0) {
+			this.setToast(`Changed on another device: ${conflicts.join(', ')}`);
+		}
+	}
+
+	private async plaintextSync() {
+		const req = this.config.sync_request();
+		const newDb = await fetchReq<Object>(req);
+		this.config.update_after_sync(newDb);
+	}
+
+	// uploads are rejected if another device uploaded first, in which case we merge again
+	private async syncVault() {
+		for (let attempt = 0; attempt < VAULT_ATTEMPTS; attempt++) {
+			const vault = await fetchReq<Object|null>(this.config.vault_request());
+			const sync = this.config.vault_sync(vault);
+			const response = await fetchReq<VaultResponse>(this.config.vault_upload_request(sync));
+			if (response.accepted) {
+				this.config.update_after_vault_sync(sync);
+				return;
+			}
+			sync.free();
+		}
+		throw new Error("The vault kept changing during sync, please try again");
+	}
 	
 	setToast(message: string | null) {
 		console.log("Setting toast to: ", message);
@@ -1,12 +1,20 @@
 use passe_core::auth::{Authentication, LoginRequest};
 use wasm_bindgen::prelude::*;
+use std::borrow::Cow;
 use wasm_bindgen::JsValue;
+use js_sys::JsString;
+use zeroize::Zeroizing;
 use anyhow::{Result};
+use serde::Serialize;
 use passe_core::password;
 use passe_core::password::{Password, Domain};
 
 use web_sys::{Request, RequestInit};
-use passe_core::config::{self, DomainConfig};
+use passe_core::config::{self, DomainConfig, Resolution};
+use passe_core::domain_extractor::normalize_domain;
+use passe_core::encryption::SyncKey;
+use passe_core::sync::{self, SyncResponse, Vault};
+use passe_core::verifier::{Fingerprint, Verifier};
 
 const CONTENT_TYPE: &str = "content-type";
 const AUTHORIZATION: &str = "authorization";
@@ -22,9 +30,28 @@ fn js<T>(result: Result<T>) -> JsResult<T> {
 	}
 }
 
+// Maps (including unknown fields, which are flattened into their struct)
+// become plain objects rather than a JS `Map`
+fn to_js<T: Serialize + ?Sized>(value: &T) -> JsResult<JsValue> {
+	let serializer = serde_wasm_bindgen::Serializer::new().serialize_maps_as_objects(true);
+	Ok(value.serialize(&serializer)?)
+}
+
+fn random_bytes<const N: usize>() -> JsResult<[u8; N]> {
+	let mut bytes = [0u8; N];
+	let window = web_sys::window().ok_or_else(|| JsValue::from_str("window unavailable"))?;
+	window.crypto()?.get_random_values_with_u8_array(&mut bytes)?;
+	Ok(bytes)
+}
+
 #[wasm_bindgen]
 pub struct Config(config::Config);
 
+/// Local changes merged into the encrypted vault, to be uploaded
+/// with `vault_upload_request` and then applied with `update_after_vault_sync`
+#[wasm_bindgen]
+pub struct VaultSync(sync::VaultSync);
+
 #[wasm_bindgen]
 impl Config {
 	#[wasm_bindgen]
@@ -42,11 +69,43 @@ impl Config {
 		js(self.0.serialize())
 	}
 	
-	pub fn generate_password(&self, domain: String, password: String) -> String {
-		let domain_config = self.0.for_domain(&domain);
-		password::generate(Domain(&domain), Password(&password), domain_config.underlying())
+	// Strings received from JS are wiped once we're done with them. The generated
+	// password is copied directly into a JS string, which we have no control over.
+	// Unconfigured domains use the defaults of `identity`, as selected in the form
+	pub fn generate_password(&self, domain: String, password: String, identity: Option<String>) -> JsResult<JsString> {
+		let password = Zeroizing::new(password);
+		let domain_config = js(self.0.for_domain_as(&domain, identity.as_deref()))?;
+		let resolved = self.0.resolve(&domain);
+		let generated = password::generate(Domain(&resolved), Password(&password), &domain_config.underlying())
+			.map_err(|e| JsValue::from_str(&e.to_string()))?;
+		Ok(JsString::from(generated.as_str()))
 	}
 	
+	pub fn remember_master_password(&mut self, password: String, identity: Option<String>) -> JsResult<()> {
+		let password = Zeroizing::new(password);
+		let salt = random_bytes()?;
+		let created = (js_sys::Date::now() / 1000.0) as u64;
+		self.0.set_verifier(identity.as_deref(), Verifier::new(Password(&password), salt, created));
+		Ok(())
+	}
+
+	/// true / false if the password does / doesn't match the remembered
+	/// master password, or undefined if there isn't one
+	pub fn check_master_password(&self, password: String, identity: Option<String>) -> Option<bool> {
+		let password = Zeroizing::new(password);
+		self.0.check_password(identity.as_deref(), Password(&password))
+	}
+
+	pub fn identities(&self) -> JsResult<JsValue> {
+		let names: Vec<&str> = self.0.identity_names().collect();
+		to_js(&names)
+	}
+
+	pub fn fingerprint(&self, password: String) -> JsResult<JsValue> {
+		let password = Zeroizing::new(password);
+		to_js(&Fingerprint::new(Password(&password)))
+	}
+
 	pub fn authenticate_request(&self) -> JsResult<Option<Request>> {
 		if let Some(ref auth) = self.0.data.authentication {
 			Ok(Some(authenticate_request(auth)?))
@@ -79,41 +138,181 @@ impl Config {
 		Ok(())
 	}
 
-	pub fn sync_request(&self) -> JsResult<Request> {
+	fn authed_request<T: Serialize>(&self, url: &str, data: Option<&T>) -> JsResult<Request> {
 		let auth = js(self.0.authentication())?;
-		let changes = &self.0.data.changes;
 		let opts = RequestInit::new();
-		opts.set_method("POST");
-		opts.set_body(&JsValue::from_str(&serde_json::to_string(&changes).expect("Unserializable JSON")));
-		let request = Request::new_with_str_and_init("/db", &opts)?;
+		if let Some(data) = data {
+			opts.set_method("POST");
+			opts.set_body(&JsValue::from_str(&serde_json::to_string(data).expect("Unserializable JSON")));
+		}
+		let request = Request::new_with_str_and_init(url, &opts)?;
 
 		request.headers().set(CONTENT_TYPE, JSON_TYPE)?;
 		request.headers().set(AUTHORIZATION, &serde_json::to_string(auth).expect("Unserializable JSON"))?;
 		Result::Ok(request)
 	}
 
+	pub fn sync_request(&self) -> JsResult<Request> {
+		self.authed_request("/sync", Some(&self.0.sync_request(false)))
+	}
+
 	pub fn update_after_sync(&mut self, db_json: JsValue) -> JsResult<()> {
-		let result: config::Domains = serde_wasm_bindgen::from_value(db_json)?;
-		self.0.update_after_sync(result);
+		let result: SyncResponse = serde_wasm_bindgen::from_value(db_json)?;
+		js(self.0.update_after_sync(result))
+	}
+
+	pub fn uses_encrypted_sync(&self) -> bool {
+		self.0.uses_encrypted_sync()
+	}
+
+	pub fn vault_request(&self) -> JsResult<Request> {
+		self.authed_request::<()>("/vault", None)
+	}
+
+	/// `vault_json` is the response to `vault_request`, which
+	/// is null if the account doesn't have a vault yet
+	pub fn set_sync_passphrase(&mut self, passphrase: String, vault_json: JsValue) -> JsResult<()> {
+		let passphrase = Zeroizing::new(passphrase);
+		let vault: Option<Vault> = serde_wasm_bindgen::from_value(vault_json)?;
+		let key = match vault {
+			Some(vault) => js(SyncKey::for_sealed(&passphrase, &vault.sealed))?,
+			None => js(SyncKey::new(&passphrase, random_bytes()?))?,
+		};
+		self.0.set_sync_key(key);
 		Ok(())
 	}
+
+	pub fn vault_sync(&self, vault_json: JsValue) -> JsResult<VaultSync> {
+		let vault: Option<Vault> = serde_wasm_bindgen::from_value(vault_json)?;
+		let sync = js(self.0.vault_sync(vault.as_ref(), false, random_bytes()?))?;
+		Ok(VaultSync(sync))
+	}
+
+	pub fn vault_upload_request(&self, sync: &VaultSync) -> JsResult<Request> {
+		self.authed_request("/vault", Some(&sync.0.upload))
+	}
+
+	/// Call once the upload has been accepted
+	pub fn update_after_vault_sync(&mut self, sync: VaultSync) -> JsResult<()> {
+		js(self.0.update_after_sync(sync.0.response))
+	}
 	
 	pub fn lookup(&self, domain: &str) -> JsResult<JsValue> {
-		let opt: Option<&DomainConfig> = self.0.for_domain(domain).explicit();
-		Ok(serde_wasm_bindgen::to_value(&opt)?)
+		let opt: Option<Cow<DomainConfig>> = self.0.for_domain(domain).explicit();
+		to_js(&opt)
+	}
+
+	/// The parent domain (or wildcard) an unconfigured domain inherits its config from
+	pub fn inherited_from(&self, domain: &str) -> Option<String> {
+		self.0.for_domain(domain).inherited_from().map(|from| from.to_owned())
+	}
+
+	/// The config used for a domain which isn't explicitly configured
+	pub fn default_config_for(&self, domain: &str) -> JsResult<JsValue> {
+		to_js(&self.0.for_domain(domain).underlying())
 	}
 
 	pub fn save_domain(&mut self, domain: String, domain_config_json: JsValue) -> JsResult<()> {
 		let domain_config = serde_wasm_bindgen::from_value(domain_config_json)?;
-		Ok(self.0.add(domain, domain_config))
+		js(self.0.add(domain, domain_config))
+	}
+
+	pub fn rename_domain(&mut self, from: &str, to: &str) -> JsResult<()> {
+		js(self.0.rename(from, to))
+	}
+
+	pub fn delete_domain(&mut self, domain: &str) -> JsResult<()> {
+		js(self.0.remove(domain))
+	}
+
+	/// The canonical domain, if this domain is an alias
+	pub fn alias_of(&self, domain: &str) -> Option<String> {
+		let resolved = self.0.resolve(domain);
+		if resolved == normalize_domain(domain) {
+			None
+		} else {
+			Some(resolved.into_owned())
+		}
+	}
+
+	pub fn add_alias(&mut self, alias: &str, canonical: &str) -> JsResult<()> {
+		js(self.0.add_alias(alias, canonical))
+	}
+
+	pub fn remove_alias(&mut self, alias: &str) -> JsResult<()> {
+		js(self.0.remove_alias(alias))
+	}
+
+	/// Domains with duplicate configs, which generate different passwords
+	pub fn duplicates(&self) -> JsResult<JsValue> {
+		to_js(&self.0.duplicates())
+	}
+
+	pub fn keep_duplicate(&mut self, domain: &str) -> JsResult<()> {
+		js(self.0.keep_duplicate(domain))
+	}
+
+	pub fn rotate_domain(&mut self, domain: &str) -> JsResult<u32> {
+		let timestamp = (js_sys::Date::now() / 1000.0) as u64;
+		js(self.0.rotate(domain, timestamp))
 	}
 
 	pub fn default_config(&self) -> JsResult<JsValue> {
-		Ok(serde_wasm_bindgen::to_value(&self.0.defaults)?)
+		to_js(&self.0.defaults)
+	}
+
+	pub fn save_defaults(&mut self, domain_config_json: JsValue) -> JsResult<()> {
+		let defaults = serde_wasm_bindgen::from_value(domain_config_json)?;
+		js(self.0.set_defaults(defaults))
+	}
+
+	pub fn identity_defaults(&self, identity: &str) -> JsResult<JsValue> {
+		to_js(&js(self.0.defaults_for(Some(identity)))?)
+	}
+
+	pub fn save_identity_defaults(&mut self, identity: &str, domain_config_json: JsValue) -> JsResult<()> {
+		let defaults = serde_wasm_bindgen::from_value(domain_config_json)?;
+		let timestamp = (js_sys::Date::now() / 1000.0) as u64;
+		js(self.0.set_identity_defaults(identity, defaults, timestamp))
+	}
+
+	pub fn set_app_domain(&mut self, app: &str, domain: &str) -> JsResult<()> {
+		js(self.0.set_app_domain(app, domain))
+	}
+
+	pub fn remove_app(&mut self, app: &str) -> JsResult<()> {
+		js(self.0.remove_app(app))
+	}
+
+	/// The other device's settings, if they conflict with local changes
+	pub fn settings_conflict(&self) -> JsResult<JsValue> {
+		to_js(&self.0.settings_conflict())
+	}
+
+	/// `resolution` is "local" or "remote"
+	pub fn resolve_settings_conflict(&mut self, resolution: JsValue) -> JsResult<()> {
+		let resolution: Resolution = serde_wasm_bindgen::from_value(resolution)?;
+		js(self.0.resolve_settings_conflict(resolution))
 	}
 
 	pub fn has_unsynced_changes(&self) -> bool {
-		self.0.changes().len() > 0
+		self.0.has_unsynced_changes()
+	}
+
+	pub fn conflicts(&self) -> JsResult<JsValue> {
+		let conflicts: Vec<_> = self.0.conflicts().collect();
+		to_js(&conflicts)
+	}
+
+	/// `resolution` is "local" or "remote"
+	pub fn resolve_conflict(&mut self, domain: &str, resolution: JsValue) -> JsResult<()> {
+		let resolution: Resolution = serde_wasm_bindgen::from_value(resolution)?;
+		js(self.0.resolve_conflict(domain, resolution))
+	}
+
+	pub fn pending_changes(&self) -> JsResult<JsValue> {
+		let changes: Vec<_> = self.0.pending_changes().collect();
+		to_js(&changes)
 	}
 	
 	pub fn clear_authentication(&mut self) {
@@ -123,10 +322,10 @@ impl Config {
 	pub fn domain_suggestions(&self, partial: &str) -> JsResult<JsValue> {
 		let extracted = self.0.extract_domain(partial);
 		let mut v = self.0.domains_matching(partial, 5);
-		if let Some(extracted) = extracted {
+		if let Some(extracted) = &extracted {
 			v.insert(0, extracted);
 		}
-		Ok(serde_wasm_bindgen::to_value(&v)?)
+		to_js(&v)
 	}
 }
 